@@ -1,61 +1,417 @@
-use std::path::{Component, Path};
+use std::path::{Component, Path, PathBuf};
 
 use anyhow::{bail, Context};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+use crate::{deliveries, deploy, jobs, pattern};
+
+#[derive(Debug, Deserialize, Default)]
 pub struct Config {
     #[serde(default)]
     pub credential: Credential,
 
     #[serde(default)]
     pub deploy: Vec<Deploy>,
+
+    /// Notified of every entry's deploys, in addition to each entry's own `notify`.
+    #[serde(default)]
+    pub notify: Vec<Notify>,
+
+    #[serde(default)]
+    pub retry: Retry,
+
+    /// Where state that must survive a restart is kept: the journals of deliveries
+    /// already acted on and of deploys not finished yet. Unset = kept in memory only.
+    pub state_dir: Option<PathBuf>,
+
+    /// Deliveries and runs already acted on, for ignoring redeliveries.
+    #[serde(skip)]
+    pub deliveries: deliveries::Deliveries,
+
+    /// Accepted deploys not finished yet, replayed after a restart.
+    #[serde(skip)]
+    pub jobs: jobs::Jobs,
+
+    /// On SIGTERM or SIGINT, how long running deploys get to finish before the
+    /// process exits anyway.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+
+    /// Set once shutting down: new deliveries are refused, and queued deploys stay
+    /// in the job journal for the next start.
+    #[serde(skip)]
+    pub draining: std::sync::atomic::AtomicBool,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_shutdown_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Default)]
 pub struct Deploy {
     pub repository: String,
 
-    /// Only deploy runs on this branch (`workflow_run.head_branch`).
-    /// Unset = any branch deploys; a startup warning is emitted.
-    pub branch: Option<String>,
+    /// What deploys: successful workflow runs' artifacts (the default), or the assets
+    /// of published releases, `artifact.name` then naming the asset.
+    #[serde(default)]
+    pub source: Source,
+
+    /// Only deploy runs on these branches (`workflow_run.head_branch`); see
+    /// `Patterns`. Unset = any branch deploys; a startup warning is emitted.
+    pub branch: Option<Patterns>,
+
+    /// Deploy a preview of every branch let through instead of one live site:
+    /// targets then use `{branch}` or `{branch_slug}`, each branch is queued and
+    /// ordered on its own, and a branch's preview is removed once the branch is
+    /// deleted or its pull request closed.
+    #[serde(default)]
+    pub previews: bool,
+
+    /// Only deploy runs of these workflows, matched against both the display name
+    /// (`workflow_run.name`) and the workflow file (`workflow_run.path`, e.g.
+    /// `.github/workflows/deploy.yml`), which stays unambiguous when two workflows
+    /// share a name. Unset = any workflow with matching artifacts deploys.
+    pub workflow: Option<Patterns>,
+
+    /// Only deploy releases with these tags (`source = "release"` only).
+    pub tag: Option<Patterns>,
+
+    /// This repository's own webhook secret(s); replaces (not adds to) the global
+    /// one for deliveries naming this repository. Its other entries, if any, may omit
+    /// it but can't set another.
+    pub github_webhook_secret: Option<WebhookSecrets>,
+
+    /// This repository's own (ideally fine-grained, single-repository) token, used
+    /// instead of the global one.
+    pub github_token: Option<String>,
 
-    /// Only deploy runs of the workflow with this name (`workflow_run.name`).
-    /// Unset = any workflow with matching artifacts deploys.
-    pub workflow: Option<String>,
+    /// Shell commands (`sh -c`) run in order before any artifact is downloaded; the
+    /// first failure aborts the deploy with every target untouched.
+    #[serde(default)]
+    pub pre_deploy: Vec<String>,
+
+    /// Shell commands run once every artifact is live (e.g. `systemctl --user restart
+    /// ...`). A failure fails the deploy, but the new version stays deployed.
+    #[serde(default)]
+    pub post_deploy: Vec<String>,
 
     #[serde(default)]
     pub artifact: Vec<Artifact>,
 
-    /// Serializes deploys of this entry; two runs completing back-to-back must not
-    /// race extraction into the same target directories.
+    #[serde(default)]
+    pub notify: Vec<Notify>,
+
+    /// Report deploys back to GitHub, on the run's head commit. Unset = don't.
+    pub github_report: Option<GithubReport>,
+
+    /// GitHub environment the deploys go to ("production" if unset); commit statuses
+    /// use it in their context, `lanchanto/<environment>`. Previews each go to their
+    /// own transient `<environment>/<branch>` ("preview/<branch>" if unset), so they
+    /// never mark the production deployment inactive.
+    pub environment: Option<String>,
+
+    /// Serializes deploys of this entry, holding at most one pending run (per
+    /// branch, for previews) behind the running one.
     #[serde(skip)]
-    pub lock: tokio::sync::Mutex<()>,
+    pub queue: deploy::Queue,
+
+    /// Last attempted and last successful deploy, for `GET /status`.
+    #[serde(skip)]
+    pub status: std::sync::Mutex<deploy::Status>,
+
+    /// Position of the live deploy, which older runs completing late must not replace.
+    #[serde(skip)]
+    pub live: deploy::Live,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Default)]
 pub struct Artifact {
+    /// Exact name, glob (`site-*`) or regex between slashes (`/^site-(.+)$/`) of the
+    /// artifacts (or release assets) to deploy; a glob or regex deploys every match.
     pub name: String,
+
+    /// Where to deploy. May use `{branch}`, `{branch_slug}` (lowercase, `-` for
+    /// anything but letters and digits), `{sha}`, `{run_id}`, `{tag}`, `{release}`,
+    /// the matched `{name}` and, for globs and regexes, its captures: `{capture}` (the
+    /// first), `{1}`, `{2}`, ... and named groups. Values are made path-safe.
     pub target: String,
 
     /// Paths (relative to `target`) carried over from the previous deploy: runtime
     /// state the artifact must never clobber (databases, uploads, ...).
     #[serde(default)]
     pub preserve: Vec<String>,
+
+    /// Switches this artifact to the release layout: each run is extracted into
+    /// `<target>.releases/<run id or release tag>` and `target` becomes a symlink to the live one,
+    /// flipped atomically. This many releases (the live one included) are kept.
+    pub keep_releases: Option<usize>,
+
+    /// The downloaded zip holds a single archive (say a `dist.tar.gz` packed to keep
+    /// file modes); deploy that archive's contents instead.
+    #[serde(default)]
+    pub unwrap_inner_archive: bool,
+
+    /// Mode of every extracted file (e.g. `0o644`), instead of the one recorded in the
+    /// archive; for artifacts zipped on Windows runners, which record none.
+    pub file_mode: Option<u32>,
+
+    /// Like `file_mode`, for directories (e.g. `0o755`).
+    pub dir_mode: Option<u32>,
+
+    /// Extraction limits; exceeding one fails the deploy and removes what was
+    /// extracted so far. Unset = unlimited. Total size of the extracted files:
+    pub max_unpacked_bytes: Option<u64>,
+
+    /// Number of archive entries, directories included.
+    pub max_entries: Option<u64>,
+
+    /// Extracted size over downloaded size.
+    pub max_compression_ratio: Option<u64>,
+
+    /// Path components of an entry (`a/b/c.txt` is 3).
+    pub max_path_depth: Option<usize>,
+
+    /// A `sha256sum`-style file inside the artifact (e.g. `SHA256SUMS`) listing every
+    /// other file in it; the deploy fails unless each one is listed and matches.
+    pub checksums: Option<String>,
+
+    /// Minisign public key (the base64 `RW...` line). The `checksums` file must then
+    /// come with a valid signature by it, `<checksums>.minisig`, so every file of the
+    /// artifact is authenticated before it goes live.
+    pub signing_key: Option<String>,
+
+    /// Like `Deploy::pre_deploy`, but run right before this artifact's swap, after it
+    /// has been downloaded.
+    #[serde(default)]
+    pub pre_deploy: Vec<String>,
+
+    /// Like `Deploy::post_deploy`, but run right after this artifact's swap.
+    #[serde(default)]
+    pub post_deploy: Vec<String>,
+}
+
+impl Deploy {
+    /// Whether runs of `branch` pass the `branch` gate.
+    pub fn branch_matches(&self, branch: Option<&str>) -> bool {
+        self.branch.as_ref().is_none_or(|want| want.matches(branch.as_slice()))
+    }
+
+    /// Whether runs of the workflow named `name`, defined in `path`, pass the
+    /// `workflow` gate.
+    pub fn workflow_matches(&self, name: Option<&str>, path: Option<&str>) -> bool {
+        let names: Vec<&str> = name.into_iter().chain(path).collect();
+        self.workflow.as_ref().is_none_or(|want| want.matches(&names))
+    }
+
+    /// Whether a release tagged `tag` passes the `tag` gate.
+    pub fn tag_matches(&self, tag: &str) -> bool {
+        self.tag.as_ref().is_none_or(|want| want.matches(&[tag]))
+    }
+
+    /// Whether this entry deploys per-branch previews (`previews`).
+    pub fn deploys_previews(&self) -> bool {
+        self.previews
+    }
+}
+
+/// Names a gate (`branch`, `workflow`, `tag`) lets through, written as a string or a
+/// list: exact names, globs (`release/*`) or regexes between slashes, each
+/// optionally prefixed with `!` to exclude what it matches. A name passes if it
+/// matches an inclusion (any name does when there are only exclusions) and no
+/// exclusion: `["release/*", "!release/old-*"]`.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "OneOrMany")]
+pub struct Patterns {
+    raw: Vec<String>,
+    /// Inclusions and exclusions, parsed as the config is read.
+    include: Vec<pattern::NamePattern>,
+    exclude: Vec<pattern::NamePattern>,
+}
+
+impl TryFrom<OneOrMany> for Patterns {
+    type Error = String;
+
+    fn try_from(value: OneOrMany) -> Result<Self, Self::Error> {
+        let raw = match value {
+            OneOrMany::One(pattern) => vec![pattern],
+            OneOrMany::Many(patterns) => patterns,
+        };
+        Self::new(raw).map_err(|e| format!("{e:#}"))
+    }
+}
+
+impl Patterns {
+    /// Patterns from `raw`, compiled.
+    pub fn new(raw: Vec<String>) -> anyhow::Result<Self> {
+        if raw.is_empty() || raw.iter().any(|p| p.is_empty() || p == "!") {
+            bail!("empty pattern: omit the setting to let everything through");
+        }
+        let (mut include, mut exclude) = (Vec::new(), Vec::new());
+        for pattern in &raw {
+            let invalid = || format!("invalid pattern {pattern:?}");
+            match pattern.strip_prefix('!') {
+                Some(excluded) => exclude.push(pattern::NamePattern::parse(excluded).with_context(invalid)?),
+                None => include.push(pattern::NamePattern::parse(pattern).with_context(invalid)?),
+            }
+        }
+        Ok(Self { raw, include, exclude })
+    }
+
+    /// Whether any of `names` (several names of the same thing) is let through:
+    /// one of them matches an inclusion, and none matches an exclusion.
+    pub fn matches(&self, names: &[&str]) -> bool {
+        let hit = |patterns: &[pattern::NamePattern]| {
+            patterns.iter().any(|p| names.iter().any(|name| p.captures(name).is_some()))
+        };
+        !names.is_empty() && (self.include.is_empty() || hit(&self.include)) && !hit(&self.exclude)
+    }
+
+    /// The one name let through, if that's all: a single exact inclusion.
+    pub fn exact(&self) -> Option<&str> {
+        match (self.raw.as_slice(), self.include.as_slice()) {
+            ([only], [include]) if include.is_exact() => Some(only),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// `workflow_run` events; `branch` and `workflow` gate them.
+    #[default]
+    WorkflowRun,
+    /// `release` events (`published`, prereleases excluded); `tag` gates them.
+    Release,
+}
+
+impl Source {
+    /// Variables of the run, for target templates: those a run from this source sets.
+    pub fn variables(self) -> &'static [&'static str] {
+        match self {
+            Self::WorkflowRun => &["branch", "branch_slug", "sha", "run_id", "release"],
+            Self::Release => &["tag", "run_id", "release"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GithubReport {
+    /// A Deployment with `in_progress` / `success` / `failure` statuses; needs the
+    /// token's `deployments: write` permission.
+    Deployment,
+    /// A `pending` / `success` / `failure` commit status; needs `statuses: write`.
+    CommitStatus,
+}
+
+/// Where to announce deploys.
+#[derive(Debug, Deserialize)]
+pub struct Notify {
+    pub kind: NotifyKind,
+    pub url: String,
+
+    /// Unset = all of them.
+    #[serde(default = "NotifyEvent::all")]
+    pub events: Vec<NotifyEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyKind {
+    /// A Discord channel webhook URL.
+    Discord,
+    /// A Slack incoming webhook URL.
+    Slack,
+    /// Any URL accepting a JSON POST describing the event.
+    Webhook,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    Start,
+    Success,
+    Failure,
+}
+
+impl NotifyEvent {
+    fn all() -> Vec<Self> {
+        vec![Self::Start, Self::Success, Self::Failure]
+    }
 }
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Credential {
     #[serde(default)]
-    pub github_webhook_secret: String,
+    pub github_webhook_secret: WebhookSecrets,
 
     #[serde(default)]
     pub github_token: String,
+
+    /// Bearer token for the manual operation endpoints (`/deploy/...`,
+    /// `/rollback/...`). Empty disables them.
+    #[serde(default)]
+    pub admin_token: String,
+}
+
+/// Retries of the artifact list call and of downloads, on connection errors, timeouts,
+/// 5xx and rate limiting. Waits double from `base_delay_ms` up to `max_delay_secs`,
+/// jittered; a `Retry-After` or rate-limit reset GitHub asks for is honored instead,
+/// unless longer than `max_delay_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    /// Attempts in total, the first one included; 1 disables retrying.
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_secs: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 1000,
+            max_delay_secs: 60,
+        }
+    }
+}
+
+/// One or more webhook secrets, a delivery signed with any of them being accepted, so
+/// a secret can be rotated without dropping deliveries: add the new one, switch
+/// GitHub over, then remove the old one. Written as a string or a list of strings.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "OneOrMany")]
+pub struct WebhookSecrets(pub Vec<String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for WebhookSecrets {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            // `""` has always meant "unset" for the global secret.
+            OneOrMany::One(secret) if secret.is_empty() => Self(Vec::new()),
+            OneOrMany::One(secret) => Self(vec![secret]),
+            OneOrMany::Many(secrets) => Self(secrets),
+        }
+    }
+}
+
+impl WebhookSecrets {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 impl Config {
     /// Loads the config file, filling unset credentials from the environment
-    /// (`GITHUB_WEBHOOK_SECRET`, `GITHUB_TOKEN`).
+    /// (`GITHUB_WEBHOOK_SECRET`, `GITHUB_TOKEN`, `LANCHANTO_ADMIN_TOKEN`).
     pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let path = path.as_ref();
         let data = std::fs::read_to_string(path)
@@ -66,7 +422,7 @@ impl Config {
         let credential = &mut config.credential;
         if credential.github_webhook_secret.is_empty() {
             if let Ok(secret) = std::env::var("GITHUB_WEBHOOK_SECRET") {
-                credential.github_webhook_secret = secret;
+                credential.github_webhook_secret = WebhookSecrets(vec![secret]);
             }
         }
         if credential.github_token.is_empty() {
@@ -74,9 +430,93 @@ impl Config {
                 credential.github_token = token;
             }
         }
+        if credential.admin_token.is_empty() {
+            if let Ok(token) = std::env::var("LANCHANTO_ADMIN_TOKEN") {
+                credential.admin_token = token;
+            }
+        }
 
-        for deploy in &config.deploy {
+        if config.retry.max_attempts == 0 {
+            bail!("invalid retry.max_attempts: must be at least 1");
+        }
+        if config.credential.github_webhook_secret.0.iter().any(String::is_empty) {
+            bail!("empty entry in the global github_webhook_secret list");
+        }
+        // Deliveries are verified before knowing which entry they're for.
+        for (i, deploy) in config.deploy.iter().enumerate() {
+            let Some(secret) = &deploy.github_webhook_secret else {
+                continue;
+            };
+            let other = config.deploy[..i]
+                .iter()
+                .filter(|d| d.repository == deploy.repository)
+                .find_map(|d| d.github_webhook_secret.as_ref());
+            if other.is_some_and(|other| other != secret) {
+                bail!("entries for {} set different github_webhook_secrets: set it on one of them", deploy.repository);
+            }
+        }
+        for deploy in &mut config.deploy {
+            let empty_secret = deploy
+                .github_webhook_secret
+                .as_ref()
+                .is_some_and(|s| s.is_empty() || s.0.iter().any(String::is_empty));
+            if empty_secret || deploy.github_token.as_deref() == Some("") {
+                bail!(
+                    "empty github_webhook_secret or github_token for {}: omit it to use the global one",
+                    deploy.repository
+                );
+            }
+            if deploy.previews && deploy.source != Source::WorkflowRun {
+                bail!("previews for {} need source = \"workflow_run\": releases have no branch", deploy.repository);
+            }
             for artifact in &deploy.artifact {
+                if artifact.keep_releases == Some(0) {
+                    bail!(
+                        "invalid keep_releases for artifact {} of {}: must keep at least the live release",
+                        artifact.name, deploy.repository
+                    );
+                }
+                let name_pattern = pattern::NamePattern::parse(&artifact.name)
+                    .with_context(|| format!("invalid artifact name for {}", deploy.repository))?;
+                let known = name_pattern.variables();
+                let placeholders = pattern::placeholders(&artifact.target)
+                    .with_context(|| format!("invalid target for artifact {} of {}", artifact.name, deploy.repository))?;
+                if deploy.previews && !placeholders.iter().any(|p| *p == "branch" || *p == "branch_slug") {
+                    bail!(
+                        "the target of artifact {} of {} must use {{branch}} or {{branch_slug}}: with previews, every branch would deploy over the others",
+                        artifact.name, deploy.repository
+                    );
+                }
+                for placeholder in placeholders {
+                    if !known.iter().any(|k| k == placeholder) && !deploy.source.variables().contains(&placeholder) {
+                        bail!(
+                            "unknown placeholder {{{}}} in the target of artifact {} of {} (its runs set {})",
+                            placeholder,
+                            artifact.name,
+                            deploy.repository,
+                            deploy.source.variables().join(", ")
+                        );
+                    }
+                }
+                if let Some(key) = &artifact.signing_key {
+                    if artifact.checksums.is_none() {
+                        bail!(
+                            "signing_key for artifact {} of {} needs `checksums`: the signature covers that file",
+                            artifact.name, deploy.repository
+                        );
+                    }
+                    if let Err(e) = minisign_verify::PublicKey::from_base64(key) {
+                        bail!("invalid signing_key for artifact {} of {}: {}", artifact.name, deploy.repository, e);
+                    }
+                }
+                if let Some(checksums) = &artifact.checksums {
+                    if !Path::new(checksums).components().all(|c| matches!(c, Component::Normal(_))) || checksums.is_empty() {
+                        bail!(
+                            "invalid checksums path {:?} for artifact {} of {}: must be a relative path inside the artifact",
+                            checksums, artifact.name, deploy.repository
+                        );
+                    }
+                }
                 for rel in &artifact.preserve {
                     let is_relative_normal = !rel.is_empty()
                         && Path::new(rel).components().all(|c| matches!(c, Component::Normal(_)));
@@ -90,8 +530,43 @@ impl Config {
             }
         }
 
+        config.deliveries = deliveries::Deliveries::open(config.state_dir.as_deref())
+            .context("failed to load the delivery journal from state_dir")?;
+        config.jobs = jobs::Jobs::open(config.state_dir.as_deref()).context("failed to open the job journal in state_dir")?;
+        if let Some(state_dir) = &config.state_dir {
+            let dir = state_dir.join("live");
+            std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+            for (i, deploy) in config.deploy.iter_mut().enumerate() {
+                deploy.live = deploy::Live::open(dir.join(format!("{i}.json")), deploy)?;
+            }
+        }
+
         Ok(config)
     }
+
+    /// The webhook secrets for deliveries naming `repository`: its entries' own, else
+    /// the global ones (also used for repositories without an entry).
+    pub fn webhook_secrets_for(&self, repository: &str) -> &[String] {
+        self.deploy
+            .iter()
+            .filter(|d| d.repository == repository)
+            .find_map(|d| d.github_webhook_secret.as_ref())
+            .unwrap_or(&self.credential.github_webhook_secret)
+            .0
+            .as_slice()
+    }
+
+    /// The entry that manual redeploys and rollbacks of `repository` act on, with its
+    /// index: the first one not deploying previews, else the first one.
+    pub fn main_entry(&self, repository: &str) -> Option<(usize, &Deploy)> {
+        let mut entries = self.deploy.iter().enumerate().filter(|(_, d)| d.repository == repository);
+        entries.clone().find(|(_, d)| !d.deploys_previews()).or_else(|| entries.next())
+    }
+
+    /// The GitHub token for `deploy`: its own, else the global one.
+    pub fn github_token_for<'a>(&'a self, deploy: &'a Deploy) -> &'a str {
+        deploy.github_token.as_deref().unwrap_or(&self.credential.github_token)
+    }
 }
 
 #[cfg(test)]
@@ -152,4 +627,179 @@ preserve = {preserve}
     fn load_rejects_empty_preserve_entry() {
         assert_invalid_preserve(r#"[""]"#);
     }
+
+    #[test]
+    fn load_rejects_zero_keep_releases() {
+        let toml = config_with_preserve("[]") + "keep_releases = 0\n";
+        let err = load_from_toml(&toml).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid keep_releases"), "got: {err:#}");
+    }
+
+    #[test]
+    fn load_checks_target_placeholders() {
+        let toml = |name: &str, target: &str| {
+            format!("[[deploy]]\nrepository = \"a/b\"\n\n[[deploy.artifact]]\nname = \"{name}\"\ntarget = \"{target}\"\n")
+        };
+        load_from_toml(&toml("site-*", "/var/www/{capture}/{branch}")).unwrap();
+        load_from_toml(&toml("/^site-(?P<locale>.+)$/", "/var/www/{locale}")).unwrap();
+
+        let err = load_from_toml(&toml("site.zip", "/var/www/{capture}")).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown placeholder {capture}"), "got: {err:#}");
+        let err = load_from_toml(&toml("/^site-(?P<locale>.+)$/", "/var/www/{lang}")).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown placeholder {lang}"), "got: {err:#}");
+
+        // Run variables depend on the source.
+        let err = load_from_toml(&toml("site.zip", "/var/www/{tag}")).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown placeholder {tag}"), "got: {err:#}");
+        let release = |target: &str| toml("site.zip", target).replace("repository = \"a/b\"\n", "repository = \"a/b\"\nsource = \"release\"\n");
+        load_from_toml(&release("/var/www/{tag}/{run_id}")).unwrap();
+        for target in ["/var/www/{branch}", "/var/www/{branch_slug}", "/var/www/{sha}"] {
+            let err = load_from_toml(&release(target)).unwrap_err();
+            assert!(format!("{err:#}").contains("unknown placeholder"), "got: {err:#}");
+        }
+    }
+
+    #[test]
+    fn gates_take_pattern_lists_with_exclusions() {
+        let toml = |gates: &str| format!("[[deploy]]\nrepository = \"a/b\"\n{gates}\n");
+        let config = load_from_toml(&toml(
+            r#"branch = ["main", "release/*", "!release/old-*"]
+workflow = ".github/workflows/deploy.yml"
+tag = "!*-rc*""#,
+        ))
+        .unwrap();
+        let deploy = &config.deploy[0];
+        assert!(deploy.branch_matches(Some("main")));
+        assert!(deploy.branch_matches(Some("release/2.0")));
+        assert!(!deploy.branch_matches(Some("release/old-1")));
+        assert!(!deploy.branch_matches(Some("feature")));
+        assert!(!deploy.branch_matches(None));
+        assert!(!deploy.deploys_previews(), "previews are opt-in, whatever the branch gate");
+
+        assert!(deploy.workflow_matches(Some("Deploy"), Some(".github/workflows/deploy.yml")));
+        assert!(!deploy.workflow_matches(Some("Deploy"), Some(".github/workflows/nightly.yml")));
+
+        assert!(deploy.tag_matches("v2.0"));
+        assert!(!deploy.tag_matches("v2.0-rc1"));
+
+        let single = load_from_toml(&toml("branch = \"main\"")).unwrap();
+        assert_eq!(single.deploy[0].branch.as_ref().unwrap().exact(), Some("main"));
+        assert!(!single.deploy[0].deploys_previews());
+        let previews = load_from_toml(&toml("branch = \"main\"\npreviews = true")).unwrap();
+        assert!(previews.deploy[0].deploys_previews());
+        let artifact = |target: &str| format!("previews = true\n\n[[deploy.artifact]]\nname = \"site\"\ntarget = \"{target}\"");
+        load_from_toml(&toml(&artifact("/var/www/{branch_slug}/site"))).unwrap();
+        let err = load_from_toml(&toml(&artifact("/var/www/site"))).unwrap_err();
+        assert!(format!("{err:#}").contains("must use {branch} or {branch_slug}"), "got: {err:#}");
+
+        for bad in ["branch = []", "workflow = [\"!\"]", "tag = \"v[1\""] {
+            let err = load_from_toml(&toml(bad)).unwrap_err();
+            assert!(format!("{err:#}").contains("pattern"), "{bad}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn load_rejects_zero_retry_attempts() {
+        let toml = "[retry]\nmax_attempts = 0\n".to_owned() + &config_with_preserve("[]");
+        let err = load_from_toml(&toml).unwrap_err();
+        assert!(format!("{err:#}").contains("retry.max_attempts"), "got: {err:#}");
+    }
+
+    #[test]
+    fn load_validates_signing_key() {
+        let toml = config_with_preserve("[]") + "signing_key = \"RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3\"\n";
+        let err = load_from_toml(&toml).unwrap_err();
+        assert!(format!("{err:#}").contains("needs `checksums`"), "got: {err:#}");
+
+        let with_sums = toml + "checksums = \"SHA256SUMS\"\n";
+        load_from_toml(&with_sums).unwrap();
+
+        let toml = config_with_preserve("[]") + "checksums = \"SHA256SUMS\"\nsigning_key = \"not a key\"\n";
+        let err = load_from_toml(&toml).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid signing_key"), "got: {err:#}");
+    }
+
+    #[test]
+    fn per_repository_credentials_fall_back_to_global() {
+        let config = load_from_toml(
+            r#"
+[credential]
+github_webhook_secret = "global-secret"
+github_token = "global-token"
+
+[[deploy]]
+repository = "a/own"
+github_webhook_secret = "own-secret"
+github_token = "own-token"
+
+[[deploy]]
+repository = "a/shared"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.webhook_secrets_for("a/own"), ["own-secret"]);
+        assert_eq!(config.webhook_secrets_for("a/shared"), ["global-secret"]);
+        assert_eq!(config.webhook_secrets_for("a/unknown"), ["global-secret"]);
+        assert_eq!(config.github_token_for(&config.deploy[0]), "own-token");
+        assert_eq!(config.github_token_for(&config.deploy[1]), "global-token");
+    }
+
+    #[test]
+    fn entries_of_one_repository_share_its_secret() {
+        let config = load_from_toml(
+            r#"
+[[deploy]]
+repository = "a/b"
+branch = "main"
+
+[[deploy]]
+repository = "a/b"
+branch = "feature/*"
+previews = true
+github_webhook_secret = "own-secret"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.webhook_secrets_for("a/b"), ["own-secret"]);
+        assert_eq!(config.main_entry("a/b").map(|(i, _)| i), Some(0));
+
+        let err = load_from_toml(
+            "[[deploy]]\nrepository = \"a/b\"\ngithub_webhook_secret = \"one\"\n\n[[deploy]]\nrepository = \"a/b\"\ngithub_webhook_secret = \"two\"\n",
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("different github_webhook_secrets"), "got: {err:#}");
+    }
+
+    #[test]
+    fn load_rejects_empty_per_repository_secret() {
+        let err = load_from_toml("[[deploy]]\nrepository = \"a/b\"\ngithub_webhook_secret = \"\"\n").unwrap_err();
+        assert!(format!("{err:#}").contains("empty github_webhook_secret"), "got: {err:#}");
+    }
+
+    #[test]
+    fn webhook_secret_accepts_string_or_list() {
+        let config = load_from_toml(
+            r#"
+[credential]
+github_webhook_secret = ["old-secret", "new-secret"]
+
+[[deploy]]
+repository = "a/b"
+github_webhook_secret = "own-secret"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.webhook_secrets_for("a/other"), ["old-secret", "new-secret"]);
+        assert_eq!(config.webhook_secrets_for("a/b"), ["own-secret"]);
+    }
+
+    #[test]
+    fn load_rejects_empty_secret_in_list() {
+        let err = load_from_toml("[[deploy]]\nrepository = \"a/b\"\ngithub_webhook_secret = [\"ok\", \"\"]\n").unwrap_err();
+        assert!(format!("{err:#}").contains("empty github_webhook_secret"), "got: {err:#}");
+        let err = load_from_toml("[[deploy]]\nrepository = \"a/b\"\ngithub_webhook_secret = []\n").unwrap_err();
+        assert!(format!("{err:#}").contains("empty github_webhook_secret"), "got: {err:#}");
+    }
 }
@@ -0,0 +1,207 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+/// How many keys are remembered; the oldest are forgotten first. GitHub redelivers
+/// within hours, and a few hundred deploys' worth of history covers that easily.
+const CAPACITY: usize = 1024;
+
+/// Deliveries and runs already acted on, so that a redelivery (same
+/// `X-GitHub-Delivery` GUID) or a second hook delivering the same run (same run id
+/// and attempt) doesn't deploy twice. Kept in memory and, with `state_dir` set,
+/// appended to a journal there so a restart doesn't forget them. A failed deploy
+/// releases its keys again (journaled as `-<key>`), so redelivering it retries.
+#[derive(Debug, Default)]
+pub struct Deliveries {
+    seen: Mutex<Seen>,
+    journal: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    order: VecDeque<String>,
+    keys: HashSet<String>,
+    /// Lines in the journal, compacted back to `order` once past twice `CAPACITY`.
+    journal_lines: usize,
+}
+
+impl Seen {
+    fn insert(&mut self, key: String) {
+        if self.keys.insert(key.clone()) {
+            self.order.push_back(key);
+        }
+        while self.order.len() > CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if self.keys.remove(key) {
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+impl Deliveries {
+    /// Loads the journal in `state_dir` (creating the directory), or remembers in
+    /// memory only without one.
+    pub fn open(state_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(state_dir) = state_dir else {
+            return Ok(Self::default());
+        };
+        fs::create_dir_all(state_dir)?;
+        let journal = state_dir.join("deliveries");
+
+        let mut seen = Seen::default();
+        match File::open(&journal) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if let Some(released) = line.strip_prefix('-') {
+                        seen.remove(released);
+                    } else if !line.is_empty() {
+                        seen.insert(line);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        compact(&journal, &mut seen)?;
+
+        Ok(Self { seen: Mutex::new(seen), journal: Some(journal) })
+    }
+
+    /// Records `keys` (`delivery:<guid>`, `run:<repository>:<id>:<attempt>`, ...)
+    /// unless one of them was recorded before, in which case that one is returned and
+    /// nothing is recorded: the delivery is a duplicate. Atomic, so of two identical
+    /// deliveries arriving at once exactly one goes through.
+    pub fn claim(&self, keys: &[String]) -> Option<String> {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(duplicate) = keys.iter().find(|key| seen.keys.contains(*key)) {
+            return Some(duplicate.clone());
+        }
+        for key in keys {
+            seen.insert(key.clone());
+        }
+
+        if let Some(journal) = &self.journal {
+            // Losing the journal only loses protection across restarts; the deploy
+            // itself must not fail over it.
+            if let Err(e) = append(journal, keys, "", &mut seen) {
+                eprintln!("! Warning: failed to write the delivery journal {}: {:#}", journal.display(), e);
+            }
+        }
+        None
+    }
+
+    /// Forgets `keys` claimed for a deploy that then failed, so that redelivering
+    /// it deploys again instead of being ignored as a duplicate.
+    pub fn release(&self, keys: &[String]) {
+        if keys.is_empty() {
+            return;
+        }
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        for key in keys {
+            seen.remove(key);
+        }
+        if let Some(journal) = &self.journal {
+            if let Err(e) = append(journal, keys, "-", &mut seen) {
+                eprintln!("! Warning: failed to write the delivery journal {}: {:#}", journal.display(), e);
+            }
+        }
+    }
+}
+
+/// Appends `keys` to the journal, each line starting with `prefix` (`-` releases).
+fn append(journal: &Path, keys: &[String], prefix: &str, seen: &mut Seen) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(journal)?;
+    for key in keys {
+        writeln!(file, "{prefix}{key}")?;
+    }
+    seen.journal_lines += keys.len();
+    if seen.journal_lines > 2 * CAPACITY {
+        compact(journal, seen)?;
+    }
+    Ok(())
+}
+
+/// Rewrites the journal to hold exactly the remembered keys, via a rename so a
+/// crash midway leaves the old journal intact.
+fn compact(journal: &Path, seen: &mut Seen) -> anyhow::Result<()> {
+    let tmp = journal.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for key in &seen.order {
+        writeln!(file, "{key}")?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, journal)?;
+    seen.journal_lines = seen.order.len();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn claim_rejects_any_seen_key() {
+        let deliveries = Deliveries::open(None).unwrap();
+        assert_eq!(deliveries.claim(&keys(&["delivery:a", "run:x/y:1:1"])), None);
+        assert_eq!(deliveries.claim(&keys(&["delivery:a"])).as_deref(), Some("delivery:a"));
+        // A second hook: new GUID, same run.
+        assert_eq!(deliveries.claim(&keys(&["delivery:b", "run:x/y:1:1"])).as_deref(), Some("run:x/y:1:1"));
+        // Nothing of a rejected claim is recorded.
+        assert_eq!(deliveries.claim(&keys(&["delivery:b", "run:x/y:1:2"])), None);
+    }
+
+    #[test]
+    fn released_keys_can_be_claimed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let deliveries = Deliveries::open(Some(dir.path())).unwrap();
+        assert_eq!(deliveries.claim(&keys(&["delivery:a", "run:x/y:1:1"])), None);
+        assert_eq!(deliveries.claim(&keys(&["delivery:b", "run:x/y:2:1"])), None);
+        deliveries.release(&keys(&["delivery:a", "run:x/y:1:1"]));
+        assert_eq!(deliveries.claim(&keys(&["delivery:a"])), None);
+
+        // The release is journaled too.
+        let reopened = Deliveries::open(Some(dir.path())).unwrap();
+        assert_eq!(reopened.claim(&keys(&["delivery:c", "run:x/y:1:1"])), None);
+        assert!(reopened.claim(&keys(&["run:x/y:2:1"])).is_some());
+    }
+
+    #[test]
+    fn oldest_keys_are_forgotten() {
+        let deliveries = Deliveries::open(None).unwrap();
+        for i in 0..=CAPACITY {
+            assert_eq!(deliveries.claim(&[format!("delivery:{i}")]), None);
+        }
+        assert_eq!(deliveries.claim(&keys(&["delivery:0"])), None);
+        assert!(deliveries.claim(&[format!("delivery:{CAPACITY}")]).is_some());
+    }
+
+    #[test]
+    fn journal_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join("state");
+
+        let deliveries = Deliveries::open(Some(&state_dir)).unwrap();
+        for i in 0..3 * CAPACITY {
+            assert_eq!(deliveries.claim(&[format!("delivery:{i}")]), None);
+        }
+        let lines = fs::read_to_string(state_dir.join("deliveries")).unwrap().lines().count();
+        assert!(lines <= 2 * CAPACITY, "journal not compacted: {lines} lines");
+
+        let reopened = Deliveries::open(Some(&state_dir)).unwrap();
+        assert!(reopened.claim(&[format!("delivery:{}", 3 * CAPACITY - 1)]).is_some());
+        assert_eq!(reopened.claim(&keys(&["delivery:0"])), None);
+    }
+}
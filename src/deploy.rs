@@ -0,0 +1,644 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::PoisonError;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, download, github, hooks, notify, pattern};
+
+/// The workflow run (or published release) being deployed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Run {
+    /// Run id, or release id for a release.
+    pub id: u64,
+    pub head_sha: Option<String>,
+    pub head_branch: Option<String>,
+    pub workflow: Option<String>,
+    /// Tag of a release.
+    pub tag: Option<String>,
+    pub position: Position,
+    pub listing: Listing,
+    /// REST API URL of the repository, the base of status reporting calls.
+    pub repository_url: String,
+    /// Id of the run's job in `config.jobs`, once journaled.
+    #[serde(skip)]
+    pub job: Option<u64>,
+    /// Delivery journal keys claimed for the run (see `main::claim`), released if the
+    /// deploy fails so that redelivering it retries.
+    #[serde(default)]
+    pub claims: Vec<String>,
+}
+
+/// Where a workflow run stands in its branch's history, so that runs completing out
+/// of order never deploy an older build over a newer one. Unknown for releases,
+/// which always deploy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    /// The head commit's timestamp, in Unix seconds.
+    pub commit_time: Option<i64>,
+    /// When the run started, in Unix seconds. Unlike run numbers, which count per
+    /// workflow, comparable across the workflows an entry deploys.
+    pub started_at: Option<i64>,
+}
+
+impl Position {
+    /// Whether this run is older than `live`: by head commit time, or by run start
+    /// time when either commit time is unknown. A run of the same commit is not
+    /// older, so re-runs and redeploys go through.
+    pub fn is_older_than(&self, live: &Position) -> bool {
+        match (self.commit_time, live.commit_time) {
+            (Some(time), Some(live_time)) => time < live_time,
+            _ => matches!((self.started_at, live.started_at), (Some(started), Some(live_started)) if started < live_started),
+        }
+    }
+}
+
+/// Where the files to deploy are listed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Listing {
+    /// `workflow_run.artifacts_url`: the run's (zipped) artifacts.
+    Artifacts(String),
+    /// `release.assets_url`: the release's assets.
+    ReleaseAssets(String),
+}
+
+impl Default for Listing {
+    fn default() -> Self {
+        Self::Artifacts(String::new())
+    }
+}
+
+impl Run {
+    /// Directory name of this run's release in the release layout: the tag of a
+    /// release (made path-safe), else the run id.
+    pub fn release_name(&self) -> String {
+        match pattern::path_safe(self.tag.as_deref().unwrap_or_default()) {
+            safe if safe.is_empty() => self.id.to_string(),
+            safe => safe,
+        }
+    }
+
+    /// The run's variables for target templates (`config::Source::variables`).
+    pub fn template_vars(&self) -> HashMap<String, String> {
+        HashMap::from([
+            ("branch".to_string(), pattern::branch_component(self.head_branch.as_deref().unwrap_or_default())),
+            ("branch_slug".to_string(), pattern::slug(self.head_branch.as_deref().unwrap_or_default())),
+            ("sha".to_string(), self.head_sha.clone().unwrap_or_default()),
+            ("run_id".to_string(), self.id.to_string()),
+            ("tag".to_string(), self.tag.clone().unwrap_or_default()),
+            ("release".to_string(), self.release_name()),
+        ])
+    }
+}
+
+/// One entry's deploys: one runs at a time, since two runs completing back-to-back
+/// must not race extraction into the same target directories, and of those waiting
+/// only the newest is kept. On a busy day, five runs queued behind a deploy would
+/// otherwise each download and go live only to be replaced seconds later.
+#[derive(Debug, Default)]
+pub struct Queue {
+    running: tokio::sync::Mutex<()>,
+    /// The run waiting to deploy next, by `live_key`.
+    pending: std::sync::Mutex<HashMap<String, Run>>,
+}
+
+impl Queue {
+    /// Makes `run` the one pending for its `key`, returning the one it supersedes,
+    /// or `run` itself if the pending one is newer.
+    pub fn push(&self, key: String, run: Run) -> Option<Run> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(waiting) = pending.get(&key) {
+            if run.position.is_older_than(&waiting.position) {
+                println!("> Dropping run {}: the newer run {} is already queued.", run.id, waiting.id);
+                return Some(run);
+            }
+            println!("> Dropping queued run {}: superseded by run {}.", waiting.id, run.id);
+        }
+        pending.insert(key, run)
+    }
+
+    /// Waits for the running deploy (or rollback, or preview removal) to finish, and
+    /// holds off the next one until the guard is dropped.
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.running.lock().await
+    }
+
+    /// The run pending for `key`, if no earlier turn already took it.
+    pub fn take(&self, key: &str) -> Option<Run> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner).remove(key)
+    }
+}
+
+/// Position of an entry's live deploy, per branch for previews (`""` otherwise):
+/// older runs completing late must not replace it. With `state_dir` set, it is saved
+/// in `<state_dir>/live/<entry index>.json` on every change, so a restart keeps it.
+#[derive(Debug, Default)]
+pub struct Live {
+    positions: std::sync::Mutex<HashMap<String, Position>>,
+    saved: Option<(PathBuf, SavedLive)>,
+}
+
+/// Whose positions a saved file holds: entries are numbered, and the config may have
+/// been reordered since.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SavedLive {
+    repository: String,
+    source: config::Source,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LiveFile {
+    #[serde(flatten)]
+    owner: SavedLive,
+    positions: HashMap<String, Position>,
+}
+
+impl Live {
+    /// Loads the positions saved in `path` for `deploy_conf`, if any, and saves there
+    /// from now on. A file left by another entry is ignored (and replaced on the next
+    /// save).
+    pub fn open(path: PathBuf, deploy_conf: &config::Deploy) -> anyhow::Result<Self> {
+        let owner = SavedLive { repository: deploy_conf.repository.clone(), source: deploy_conf.source };
+        let positions = match fs::read(&path) {
+            Ok(contents) => {
+                let file: LiveFile =
+                    serde_json::from_slice(&contents).with_context(|| format!("invalid live positions in {}", path.display()))?;
+                if file.owner == owner {
+                    file.positions
+                } else {
+                    eprintln!(
+                        "! Warning: ignoring {}: it holds the live positions of another entry ({}).",
+                        path.display(),
+                        file.owner.repository
+                    );
+                    HashMap::new()
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(Self { positions: std::sync::Mutex::new(positions), saved: Some((path, owner)) })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Position> {
+        self.positions.lock().unwrap_or_else(PoisonError::into_inner).get(key).copied()
+    }
+
+    pub fn set(&self, key: String, position: Position) {
+        self.update(|positions| {
+            positions.insert(key, position);
+        });
+    }
+
+    pub fn clear(&self) {
+        self.update(HashMap::clear);
+    }
+
+    /// Changes the positions and saves them. A file that can't be written is logged,
+    /// not fatal: the positions still hold until a restart.
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, Position>)) {
+        let mut positions = self.positions.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut positions);
+        let Some((path, owner)) = &self.saved else {
+            return;
+        };
+        let file = LiveFile { owner: owner.clone(), positions: positions.clone() };
+        if let Err(e) = write_live(path, &file) {
+            eprintln!("! Warning: failed to save the live positions of {}: {:#}", owner.repository, e);
+        }
+    }
+}
+
+/// Writes via a synced rename, so neither a crash nor a power loss leaves a torn
+/// file behind.
+fn write_live(path: &Path, file: &LiveFile) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut out = File::create(&tmp).with_context(|| format!("failed to write {}", tmp.display()))?;
+    out.write_all(&serde_json::to_vec(file)?)?;
+    out.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// What `GET /status` reports for one entry. In memory only: a restart forgets it.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Status {
+    pub last_attempt: Option<Record>,
+    pub last_success: Option<Record>,
+    /// Set by a rollback, until the next successful deploy: `last_success` is then
+    /// no longer what's live.
+    pub rolled_back: Option<RolledBack>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RolledBack {
+    /// Unix time, in seconds.
+    pub at: u64,
+    /// The version now live, by artifact name.
+    pub versions: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    pub run_id: u64,
+    pub head_sha: Option<String>,
+    pub branch: Option<String>,
+    pub workflow: Option<String>,
+    pub tag: Option<String>,
+    /// Unix time, in seconds.
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub outcome: Outcome,
+    /// The full `anyhow` chain of a failed deploy.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Running,
+    Success,
+    Failure,
+}
+
+/// Deploys `run`, recording the attempt and its outcome in `deploy_conf.status`,
+/// announcing them to the `notify` targets and reporting them to GitHub.
+pub async fn run(config: &config::Config, deploy_conf: &config::Deploy, run: &Run) -> anyhow::Result<()> {
+    let live_key = live_key(deploy_conf, run);
+    let live = deploy_conf.live.get(&live_key);
+    if let Some(live) = live.filter(|live| run.position.is_older_than(live)) {
+        println!(
+            "> Not deploying run {} of {}: it is older than the live one ({:?} < {:?}).",
+            run.id, deploy_conf.repository, run.position, live
+        );
+        return Ok(());
+    }
+
+    let token = config.github_token_for(deploy_conf);
+    let started = Instant::now();
+    let mut record = Record {
+        run_id: run.id,
+        head_sha: run.head_sha.clone(),
+        branch: run.head_branch.clone(),
+        workflow: run.workflow.clone(),
+        tag: run.tag.clone(),
+        started_at: unix_now(),
+        finished_at: None,
+        outcome: Outcome::Running,
+        error: None,
+    };
+    update_status(deploy_conf, |status| status.last_attempt = Some(record.clone()));
+    notify::send(config, deploy_conf, run, &notify::Event::Start).await;
+    let report = github::Report::start(token, deploy_conf, run).await;
+
+    let result = deploy_artifacts(config, deploy_conf, token, run).await;
+
+    record.finished_at = Some(unix_now());
+    match &result {
+        Ok(()) => {
+            record.outcome = Outcome::Success;
+            deploy_conf.live.set(live_key, run.position);
+        }
+        Err(e) => {
+            record.outcome = Outcome::Failure;
+            record.error = Some(format!("{e:#}"));
+        }
+    }
+    update_status(deploy_conf, |status| {
+        if record.outcome == Outcome::Success {
+            status.last_success = Some(record.clone());
+            status.rolled_back = None;
+        }
+        status.last_attempt = Some(record.clone());
+    });
+
+    let duration = started.elapsed();
+    let event = match &record.error {
+        None => notify::Event::Success { duration },
+        Some(error) => notify::Event::Failure { duration, error },
+    };
+    notify::send(config, deploy_conf, run, &event).await;
+    if let Some(report) = &report {
+        report.finish(token, &deploy_conf.repository, record.error.as_deref()).await;
+    }
+
+    result
+}
+
+/// Which live deploy `run` replaces: the entry's only one, or its branch's preview.
+pub fn live_key(deploy_conf: &config::Deploy, run: &Run) -> String {
+    if deploy_conf.deploys_previews() {
+        run.head_branch.clone().unwrap_or_default()
+    } else {
+        String::new()
+    }
+}
+
+fn update_status(deploy_conf: &config::Deploy, f: impl FnOnce(&mut Status)) {
+    // A panic elsewhere while holding the lock leaves the status as readable as before.
+    f(&mut deploy_conf.status.lock().unwrap_or_else(PoisonError::into_inner));
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Deploys every artifact of `deploy_conf` from `run`, wrapped in the entry's
+/// `pre_deploy` / `post_deploy` hooks. A failing `pre_deploy` aborts before anything
+/// is downloaded, so every target is left untouched.
+async fn deploy_artifacts(config: &config::Config, deploy_conf: &config::Deploy, token: &str, run: &Run) -> anyhow::Result<()> {
+    let planned = download::plan(token, &deploy_conf.repository, run, &deploy_conf.artifact, &config.retry).await?;
+    let mut env = hook_env(deploy_conf, run);
+    let targets: Vec<&str> = planned.iter().map(|p| p.target.as_str()).collect();
+    // `:`-separated, like `PATH`.
+    env.push(("LANCHANTO_TARGETS", targets.join(":")));
+
+    hooks::run("pre_deploy", &deploy_conf.pre_deploy, &env).await?;
+    download::download_artifacts(token, &deploy_conf.repository, run, planned, &env, &config.retry).await?;
+    hooks::run("post_deploy", &deploy_conf.post_deploy, &env)
+        .await
+        .context("artifacts deployed, but a post_deploy hook failed")?;
+
+    Ok(())
+}
+
+/// Swaps every artifact of `deploy_conf` back to an earlier version (see
+/// `download::rollback`) and runs the `post_deploy` hooks, so services pick the old
+/// version up; they see `LANCHANTO_ROLLBACK=1` and no run. Returns each artifact's
+/// now-live version. Stops at the first failure: earlier artifacts stay rolled back.
+pub async fn rollback(deploy_conf: &'static config::Deploy, to: Option<&str>) -> anyhow::Result<Vec<(String, String)>> {
+    // Checked up front: failing midway would leave the rollback half done.
+    if let Some(artifact) = deploy_conf.artifact.iter().find(|a| a.target.contains('{')) {
+        bail!(
+            "cannot roll back artifact {}: its target {} is templated; roll back each deployed directory by hand",
+            artifact.name,
+            artifact.target
+        );
+    }
+    let mut env = base_env(deploy_conf);
+    let targets: Vec<&str> = deploy_conf.artifact.iter().map(|a| a.target.as_str()).collect();
+    env.push(("LANCHANTO_TARGETS", targets.join(":")));
+    env.push(("LANCHANTO_ROLLBACK", "1".to_string()));
+
+    // What's live is older now; the next run must not be held to the rolled-back one.
+    deploy_conf.live.clear();
+
+    let mut versions = Vec::with_capacity(deploy_conf.artifact.len());
+    for artifact in &deploy_conf.artifact {
+        let to = to.map(str::to_owned);
+        let version = tokio::task::spawn_blocking(move || download::rollback(artifact, to.as_deref()))
+            .await
+            .context("rollback task panicked")?
+            .with_context(|| format!("failed to roll back artifact {}", artifact.name))?;
+        println!("> Rolled {} back to {}.", artifact.target, version);
+        update_status(deploy_conf, |status| {
+            // The first swap of this rollback starts a fresh record.
+            if versions.is_empty() {
+                status.rolled_back = Some(RolledBack { at: unix_now(), versions: BTreeMap::new() });
+            }
+            if let Some(rolled_back) = &mut status.rolled_back {
+                rolled_back.versions.insert(artifact.name.clone(), version.clone());
+            }
+        });
+
+        let mut artifact_env = env.clone();
+        artifact_env.push(("LANCHANTO_ARTIFACT", artifact.name.clone()));
+        artifact_env.push(("LANCHANTO_TARGET", artifact.target.clone()));
+        hooks::run("post_deploy", &artifact.post_deploy, &artifact_env)
+            .await
+            .with_context(|| format!("artifact {} rolled back, but its post_deploy hook failed", artifact.name))?;
+
+        versions.push((artifact.name.clone(), version));
+    }
+
+    hooks::run("post_deploy", &deploy_conf.post_deploy, &env)
+        .await
+        .context("artifacts rolled back, but a post_deploy hook failed")?;
+
+    Ok(versions)
+}
+
+/// Removes what `deploy_conf` deployed for `branch`, now deleted or merged: for every
+/// artifact whose target depends on the branch, the directory its branch part names
+/// (see `pattern::branch_root`). Returns the removed paths.
+pub async fn remove_preview(deploy_conf: &'static config::Deploy, branch: &str) -> anyhow::Result<Vec<String>> {
+    let vars = HashMap::from([
+        ("branch".to_string(), pattern::branch_component(branch)),
+        ("branch_slug".to_string(), pattern::slug(branch)),
+    ]);
+    let mut removed = Vec::new();
+    for artifact in &deploy_conf.artifact {
+        let root = match pattern::branch_root(&artifact.target, &vars) {
+            Ok(Some(root)) => root,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("! Warning: not removing the {} preview of artifact {}: {:#}", branch, artifact.name, e);
+                continue;
+            }
+        };
+        let path = root.clone();
+        let existed = tokio::task::spawn_blocking(move || download::remove_target(Path::new(&path)))
+            .await
+            .context("removal task panicked")?
+            .with_context(|| format!("failed to remove preview {root}"))?;
+        if existed {
+            println!("> Removed preview {}.", root);
+            removed.push(root);
+        }
+    }
+    Ok(removed)
+}
+
+/// Cleans up after a deploy of `run` cut short by a crash or restart, for each
+/// artifact target (see `download::clean_interrupted`). Targets depending on which
+/// artifacts matched can't be known without listing them, and are left as they are.
+pub fn clean_interrupted(deploy_conf: &config::Deploy, run: &Run) {
+    let vars = run.template_vars();
+    for artifact in &deploy_conf.artifact {
+        let target = match pattern::render(&artifact.target, &vars) {
+            Ok(target) => target,
+            Err(e) => {
+                eprintln!("! Warning: not cleaning up after artifact {}: {:#}", artifact.name, e);
+                continue;
+            }
+        };
+        if let Err(e) = download::clean_interrupted(Path::new(&target), &artifact.preserve) {
+            eprintln!("! Warning: failed to clean up {} after an interrupted deploy: {:#}", target, e);
+        }
+    }
+}
+
+/// Environment handed to every hook of this deploy. Per-artifact hooks additionally
+/// get `LANCHANTO_ARTIFACT` and `LANCHANTO_TARGET`.
+fn hook_env(deploy_conf: &config::Deploy, run: &Run) -> Vec<(&'static str, String)> {
+    let mut env = base_env(deploy_conf);
+    env.extend([
+        ("LANCHANTO_BRANCH", run.head_branch.clone().unwrap_or_default()),
+        ("LANCHANTO_RUN_ID", run.id.to_string()),
+        ("LANCHANTO_COMMIT_SHA", run.head_sha.clone().unwrap_or_default()),
+        ("LANCHANTO_TAG", run.tag.clone().unwrap_or_default()),
+    ]);
+    env
+}
+
+/// The part of the hook environment that doesn't depend on a run.
+/// `LANCHANTO_TARGETS` is added by the caller, which knows the rendered targets.
+fn base_env(deploy_conf: &config::Deploy) -> Vec<(&'static str, String)> {
+    vec![("LANCHANTO_REPOSITORY", deploy_conf.repository.clone())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_positions_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.json");
+        let entry = config::Deploy { repository: "a/b".to_owned(), ..Default::default() };
+        let position = Position { commit_time: Some(1_000), started_at: None };
+
+        let live = Live::open(path.clone(), &entry).unwrap();
+        live.set("feature/x".to_owned(), position);
+        assert_eq!(Live::open(path.clone(), &entry).unwrap().get("feature/x"), Some(position));
+
+        // The config was reordered: entry 0 is another one now.
+        let other = config::Deploy { repository: "a/c".to_owned(), ..Default::default() };
+        assert_eq!(Live::open(path.clone(), &other).unwrap().get("feature/x"), None);
+
+        live.clear();
+        assert_eq!(Live::open(path, &entry).unwrap().get("feature/x"), None);
+    }
+
+    #[tokio::test]
+    async fn failed_deploy_is_recorded_as_last_attempt_only() {
+        let deploy_conf = config::Deploy {
+            repository: "test/repo".to_owned(),
+            ..Default::default()
+        };
+        let run = Run {
+            id: 7,
+            head_sha: Some("abc123".to_owned()),
+            head_branch: Some("main".to_owned()),
+            workflow: Some("CI".to_owned()),
+            listing: Listing::Artifacts("http://127.0.0.1:1/unused".to_owned()),
+            ..Default::default()
+        };
+
+        // An empty token fails the deploy before any request is made.
+        let config = config::Config::default();
+        assert!(super::run(&config, &deploy_conf, &run).await.is_err());
+
+        let status = deploy_conf.status.lock().unwrap().clone();
+        let attempt = status.last_attempt.expect("attempt recorded");
+        assert_eq!(attempt.run_id, 7);
+        assert_eq!(attempt.head_sha.as_deref(), Some("abc123"));
+        assert_eq!(attempt.outcome, Outcome::Failure);
+        assert!(attempt.error.unwrap().contains("empty github token"));
+        assert!(attempt.finished_at.is_some());
+        assert!(status.last_success.is_none());
+    }
+
+    #[tokio::test]
+    async fn older_run_never_replaces_the_live_one() {
+        let deploy_conf = config::Deploy {
+            repository: "test/repo".to_owned(),
+            ..Default::default()
+        };
+        let live = Position { commit_time: Some(2_000), started_at: Some(2_100) };
+        deploy_conf.live.set(String::new(), live);
+
+        let older = Run {
+            id: 1,
+            position: Position { commit_time: Some(1_000), started_at: Some(2_200) },
+            ..Default::default()
+        };
+        let config = config::Config::default();
+        super::run(&config, &deploy_conf, &older).await.unwrap();
+        assert!(deploy_conf.status.lock().unwrap().last_attempt.is_none());
+
+        // A re-run of the live commit is attempted (and fails on the empty token).
+        let same_commit = Run {
+            id: 2,
+            position: Position { commit_time: Some(2_000), started_at: Some(2_050) },
+            ..Default::default()
+        };
+        assert!(super::run(&config, &deploy_conf, &same_commit).await.is_err());
+        assert_eq!(deploy_conf.status.lock().unwrap().last_attempt.as_ref().unwrap().run_id, 2);
+    }
+
+    #[tokio::test]
+    async fn queue_keeps_only_the_newest_pending_run() {
+        let queue = Queue::default();
+        let run = |id, started_at| Run { id, position: Position { commit_time: None, started_at }, ..Default::default() };
+
+        let running = queue.lock().await;
+        assert!(queue.push(String::new(), run(1, Some(1))).is_none());
+        assert_eq!(queue.push(String::new(), run(3, Some(3))).map(|run| run.id), Some(1));
+        assert_eq!(queue.push(String::new(), run(2, Some(2))).map(|run| run.id), Some(2));
+        // Previews of other branches queue independently.
+        assert!(queue.push("feature".to_owned(), run(4, Some(1))).is_none());
+        drop(running);
+
+        let _turn = queue.lock().await;
+        assert_eq!(queue.take("").map(|run| run.id), Some(3));
+        assert!(queue.take("").is_none());
+        assert_eq!(queue.take("feature").map(|run| run.id), Some(4));
+    }
+
+    #[tokio::test]
+    async fn rollback_with_a_templated_target_touches_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = dir.path().join("app");
+        for (version, path) in [("v2", app.clone()), ("v1", dir.path().join(".app.old-1"))] {
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("v.txt"), version).unwrap();
+        }
+        let deploy_conf: &'static config::Deploy = Box::leak(Box::new(config::Deploy {
+            repository: "test/repo".to_owned(),
+            artifact: vec![
+                config::Artifact { name: "app".to_owned(), target: app.display().to_string(), ..Default::default() },
+                config::Artifact {
+                    name: "site-*".to_owned(),
+                    target: format!("{}/{{capture}}", dir.path().display()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }));
+
+        let err = rollback(deploy_conf, None).await.unwrap_err();
+        assert!(format!("{err:#}").contains("cannot roll back artifact site-*"), "got: {err:#}");
+        assert_eq!(std::fs::read_to_string(app.join("v.txt")).unwrap(), "v2");
+        assert!(deploy_conf.status.lock().unwrap().rolled_back.is_none());
+    }
+
+    #[test]
+    fn position_falls_back_to_run_start_time() {
+        let position = |commit_time, started_at| Position { commit_time, started_at };
+        assert!(position(Some(1), Some(9)).is_older_than(&position(Some(2), Some(1))));
+        assert!(!position(Some(2), Some(1)).is_older_than(&position(Some(2), Some(9))));
+        assert!(position(None, Some(1)).is_older_than(&position(Some(2), Some(2))));
+        assert!(!position(None, None).is_older_than(&position(Some(2), Some(2))));
+    }
+
+    #[test]
+    fn release_name_is_tag_made_path_safe_or_run_id() {
+        let run = Run { id: 42, ..Default::default() };
+        assert_eq!(run.release_name(), "42");
+
+        let run = Run { id: 42, tag: Some("v1.2.3".to_owned()), ..Default::default() };
+        assert_eq!(run.release_name(), "v1.2.3");
+
+        let run = Run { id: 42, tag: Some("../release/2024 final".to_owned()), ..Default::default() };
+        assert_eq!(run.release_name(), "-release-2024-final");
+    }
+}
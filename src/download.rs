@@ -1,32 +1,50 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{self, Seek};
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Seek};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::sync::LazyLock;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{ensure, Context};
-use tokio::io::AsyncWriteExt;
+use anyhow::{bail, ensure, Context};
+use sha2::{Digest, Sha256};
+use reqwest::StatusCode;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
-use crate::config;
+use crate::{config, deploy, hooks, pattern};
 
-#[derive(serde::Deserialize)]
+#[derive(Clone, serde::Deserialize)]
 struct ArtifactEntry {
     name: String,
     archive_download_url: String,
+    /// `sha256:<hex>` of the archive; missing for artifacts uploaded before GitHub
+    /// started recording it.
+    #[serde(default)]
+    digest: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
 struct ArtifactList {
+    #[serde(default)]
+    total_count: Option<u64>,
     #[serde(default)]
     artifacts: Vec<ArtifactEntry>,
 }
 
+#[derive(serde::Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    /// API URL; serves the asset itself given `Accept: application/octet-stream`.
+    url: String,
+    #[serde(default)]
+    digest: Option<String>,
+}
+
 /// Shared client so timeouts are enforced uniformly: reqwest has NO default timeouts,
 /// and a stalled connection would otherwise pin its deploy task forever. No total
 /// request timeout on purpose — artifact downloads may legitimately take minutes;
 /// `read_timeout` catches stalls without capping size.
-static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+pub static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
     reqwest::Client::builder()
         .user_agent("lanchanto")
         .connect_timeout(Duration::from_secs(10))
@@ -35,74 +53,341 @@ static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
         .expect("Failed to build HTTP client.")
 });
 
-pub async fn download_artifacts(token: &str, repo_full: &str, download_url: &str, artifacts: &[config::Artifact]) -> anyhow::Result<()> {
-    println!("> Fetching artifacts for {}, url={}", repo_full, download_url);
+/// One artifact (or release asset) of the run to deploy, and where it goes.
+pub struct Planned<'a> {
+    artifact: &'a config::Artifact,
+    entry: ArtifactEntry,
+    /// `artifact.target`, rendered for this entry.
+    pub target: String,
+}
 
+/// Lists the run's artifacts and matches them to `artifacts`, rendering each target,
+/// without downloading anything yet.
+pub async fn plan<'a>(
+    token: &str,
+    repo_full: &str,
+    run: &deploy::Run,
+    artifacts: &'a [config::Artifact],
+    retry: &config::Retry,
+) -> anyhow::Result<Vec<Planned<'a>>> {
     ensure!(!token.is_empty(), "empty github token");
 
-    // The default page size is 30; ask for the maximum so a run with many artifacts
-    // doesn't hide the wanted ones on a later page.
-    let list_url = format!("{download_url}?per_page=100");
-    let artifact_list: ArtifactList = CLIENT
-        .get(&list_url)
-        .bearer_auth(token)
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await
-        .context("failed to list workflow artifacts")?;
-
-    let artifact_map: HashMap<&str, &ArtifactEntry> = artifact_list
-        .artifacts
+    let patterns = artifacts
         .iter()
-        .map(|entry| (entry.name.as_str(), entry))
-        .collect();
+        .map(|a| pattern::NamePattern::parse(&a.name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (url, noun) = match &run.listing {
+        deploy::Listing::Artifacts(url) => {
+            println!("> Fetching artifacts for {}, url={}", repo_full, url);
+            (url, "run has no artifact(s)")
+        }
+        deploy::Listing::ReleaseAssets(url) => {
+            println!("> Fetching release assets for {}, url={}", repo_full, url);
+            (url, "release has no asset(s)")
+        }
+    };
+    let (entries, total) = list_entries(token, retry, &run.listing, url, &patterns).await?;
 
     // All-or-nothing: deploying a subset of the configured artifacts would leave a
-    // mixed-version deployment, so fail before touching any target.
+    // mixed-version deployment, so fail before touching any target. A pattern must
+    // match at least once; each match deploys to its own rendered target.
+    let run_vars = run.template_vars();
     let mut matched = Vec::with_capacity(artifacts.len());
     let mut missing = Vec::new();
-    for wanted in artifacts {
-        match artifact_map.get(wanted.name.as_str()) {
-            Some(&entry) => matched.push((wanted, entry)),
-            None => missing.push(wanted.name.as_str()),
+    for (wanted, pattern) in artifacts.iter().zip(&patterns) {
+        let before = matched.len();
+        for entry in &entries {
+            let Some(captures) = pattern.captures(&entry.name) else {
+                continue;
+            };
+            let mut vars = run_vars.clone();
+            vars.extend(captures);
+            let target = pattern::render(&wanted.target, &vars)
+                .with_context(|| format!("failed to render the target of artifact {}", entry.name))?;
+            matched.push(Planned { artifact: wanted, entry: entry.clone(), target });
+        }
+        if matched.len() == before {
+            missing.push(wanted.name.as_str());
         }
     }
-    ensure!(missing.is_empty(), "run has no artifact(s) named: {}", missing.join(", "));
-
-    for (wanted, entry) in matched {
-        println!("> Downloading {} to {}...", entry.name, wanted.target);
+    ensure!(
+        missing.is_empty(),
+        "{} named: {} (searched {} of {} entries)",
+        noun,
+        missing.join(", "),
+        entries.len(),
+        total.map_or_else(|| entries.len().to_string(), |total| total.to_string())
+    );
+    let mut targets = HashSet::new();
+    for planned in &matched {
+        ensure!(
+            targets.insert(&planned.target),
+            "more than one artifact deploys to {} (last: {})",
+            planned.target,
+            planned.entry.name
+        );
+    }
+    Ok(matched)
+}
 
-        let zip_file = fetch_to_temp_file(&entry.archive_download_url, token)
+/// Downloads and deploys what `plan` matched. `hook_env` is the deploy's hook
+/// environment; each artifact's own hooks run with it plus `LANCHANTO_ARTIFACT` and
+/// `LANCHANTO_TARGET`, around that artifact's swap.
+pub async fn download_artifacts(
+    token: &str,
+    repo_full: &str,
+    run: &deploy::Run,
+    planned: Vec<Planned<'_>>,
+    hook_env: &[(&str, String)],
+    retry: &config::Retry,
+) -> anyhow::Result<()> {
+    let accept = match &run.listing {
+        deploy::Listing::Artifacts(_) => None,
+        deploy::Listing::ReleaseAssets(_) => Some("application/octet-stream"),
+    };
+    for Planned { artifact: wanted, entry, target } in planned {
+        println!("> Downloading {} to {}...", entry.name, target);
+
+        let archive = fetch_to_temp_file(&entry.archive_download_url, token, accept, entry.digest.as_deref(), retry)
             .await
             .with_context(|| format!("failed to download artifact {}", entry.name))?;
 
-        let target_path = PathBuf::from(&wanted.target);
+        let mut env = hook_env.to_vec();
+        env.push(("LANCHANTO_ARTIFACT", entry.name.clone()));
+        env.push(("LANCHANTO_TARGET", target.clone()));
+        hooks::run("pre_deploy", &wanted.pre_deploy, &env)
+            .await
+            .with_context(|| format!("aborted deploy of artifact {}; target left untouched", entry.name))?;
+
+        let target_path = PathBuf::from(target);
         let preserve = wanted.preserve.clone();
-        tokio::task::spawn_blocking(move || deploy_zip(zip_file, &target_path, &preserve))
+        let keep_releases = wanted.keep_releases;
+        let unwrap_inner = wanted.unwrap_inner_archive;
+        let mut extract = Extract::from(wanted);
+        let release = run.release_name();
+        tokio::task::spawn_blocking(move || {
+            let archive = if unwrap_inner {
+                extract.downloaded_len = Some(archive.metadata()?.len());
+                inner_archive(archive, &extract)?
+            } else {
+                archive
+            };
+            match keep_releases {
+                Some(keep) => deploy_release(archive, &target_path, &release, &preserve, keep, &extract),
+                None => deploy_archive(archive, &target_path, &preserve, &extract),
+            }
+        })
+        .await
+        .context("deploy task panicked")?
+        .with_context(|| format!("failed to deploy artifact {}", entry.name))?;
+
+        hooks::run("post_deploy", &wanted.post_deploy, &env)
             .await
-            .context("deploy task panicked")?
-            .with_context(|| format!("failed to deploy artifact {}", entry.name))?;
+            .with_context(|| format!("artifact {} deployed, but its post_deploy hook failed", entry.name))?;
     }
 
     println!("> Deployed all artifacts for {} successfully!", repo_full);
     Ok(())
 }
 
+/// Lists the run's artifacts (or the release's assets) from `url`, following the
+/// `Link: rel="next"` pages until every `wanted` name has turned up or the listing
+/// ends; with any glob or regex among them, that is always the end. Also returns the
+/// `total_count` the API reports, if it does.
+async fn list_entries(
+    token: &str,
+    retry: &config::Retry,
+    listing: &deploy::Listing,
+    url: &str,
+    wanted: &[pattern::NamePattern],
+) -> anyhow::Result<(Vec<ArtifactEntry>, Option<u64>)> {
+    let mut entries: Vec<ArtifactEntry> = Vec::new();
+    let mut total = None;
+    // The default page size is 30; ask for the maximum to keep the page count down.
+    let mut next = Some(format!("{url}?per_page=100"));
+    while let Some(url) = next.take() {
+        let response = send_with_retry(retry, || CLIENT.get(&url).bearer_auth(token)).await?;
+        next = next_link(&response);
+        match listing {
+            deploy::Listing::Artifacts(_) => {
+                let list: ArtifactList = response.json().await.context("failed to list workflow artifacts")?;
+                total = list.total_count;
+                entries.extend(list.artifacts);
+            }
+            deploy::Listing::ReleaseAssets(_) => {
+                let assets: Vec<ReleaseAsset> = response.json().await.context("failed to list release assets")?;
+                entries.extend(assets.into_iter().map(|asset| ArtifactEntry {
+                    name: asset.name,
+                    archive_download_url: asset.url,
+                    digest: asset.digest,
+                }));
+            }
+        }
+
+        let all_found = wanted
+            .iter()
+            .all(|pattern| pattern.is_exact() && entries.iter().any(|entry| pattern.captures(&entry.name).is_some()));
+        if all_found {
+            break;
+        }
+    }
+    Ok((entries, total))
+}
+
+/// The `rel="next"` URL of a paginated GitHub response's `Link` header.
+fn next_link(response: &reqwest::Response) -> Option<String> {
+    let link = response.headers().get(reqwest::header::LINK)?.to_str().ok()?;
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == r#"rel="next""#)
+            .then(|| url.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+    })
+}
+
+/// Sends the request `build` makes, retrying per `retry` while the failure looks
+/// transient: connection errors and timeouts, 5xx, and GitHub's rate limiting (429, or
+/// 403 with `Retry-After` or an exhausted `X-RateLimit-Remaining`). The response
+/// returned has a success status.
+pub async fn send_with_retry(retry: &config::Retry, build: impl Fn() -> reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+    send_counting_attempts(retry, &mut 1, build).await
+}
+
+/// `send_with_retry`, counting in `attempt` (the number of the next one) against a
+/// budget shared with the caller's own retries.
+async fn send_counting_attempts(
+    retry: &config::Retry,
+    attempt: &mut u32,
+    build: impl Fn() -> reqwest::RequestBuilder,
+) -> anyhow::Result<reqwest::Response> {
+    loop {
+        let (error, asked_wait) = match build().send().await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let asked_wait = rate_limit_wait(&response);
+                let status = response.status();
+                let transient = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || asked_wait.is_some();
+                let error = response.error_for_status().expect_err("status is not a success");
+                if !transient {
+                    return Err(error.into());
+                }
+                (error, asked_wait)
+            }
+            Err(e) if e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() => (e, None),
+            Err(e) => return Err(e.into()),
+        };
+        // Artifact downloads redirect to signed URLs, which must not end up in logs.
+        let error = error.without_url();
+
+        if *attempt >= retry.max_attempts {
+            return Err(anyhow::Error::new(error).context(format!("giving up after {attempt} attempt(s)")));
+        }
+        let wait = match asked_wait {
+            Some(wait) if wait > Duration::from_secs(retry.max_delay_secs) => {
+                return Err(anyhow::Error::new(error).context(format!("rate limited for another {}s", wait.as_secs())));
+            }
+            Some(wait) => wait,
+            None => backoff(retry, *attempt),
+        };
+        eprintln!("! Warning: {} (attempt {} of {}); retrying in {}ms", error, attempt, retry.max_attempts, wait.as_millis());
+        tokio::time::sleep(wait).await;
+        *attempt += 1;
+    }
+}
+
+/// How long GitHub asks us to wait before trying again, if it does: `Retry-After`
+/// (secondary rate limits), else the `X-RateLimit-Reset` time once
+/// `X-RateLimit-Remaining` is down to 0 (primary rate limit).
+fn rate_limit_wait(response: &reqwest::Response) -> Option<Duration> {
+    let header = |name: &str| response.headers().get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+    if let Some(secs) = header("retry-after") {
+        return Some(Duration::from_secs(secs));
+    }
+    if header("x-ratelimit-remaining") == Some(0) {
+        let reset = header("x-ratelimit-reset")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        return Some(Duration::from_secs(reset.saturating_sub(now) + 1));
+    }
+    None
+}
+
+/// The wait before retry `attempt + 1`: `base_delay_ms` doubled per attempt, capped at
+/// `max_delay_secs`, then jittered down by up to half so that deploys failing together
+/// don't retry in lockstep.
+fn backoff(retry: &config::Retry, attempt: u32) -> Duration {
+    let delay = retry
+        .base_delay_ms
+        .saturating_mul(1 << (attempt - 1).min(32))
+        .min(retry.max_delay_secs.saturating_mul(1000));
+    Duration::from_millis(delay / 2 + fastrand::u64(0..=delay / 2))
+}
+
 /// Streams the artifact archive into an unnamed temp file (reclaimed by the OS even if
 /// we crash) instead of buffering it in memory; artifacts can be hundreds of megabytes.
-async fn fetch_to_temp_file(url: &str, token: &str) -> anyhow::Result<File> {
-    let mut response = CLIENT
-        .get(url)
-        .bearer_auth(token)
-        .send()
-        .await?
-        .error_for_status()?;
-
+/// A connection dropping mid-file is resumed with a `Range` request where the server
+/// supports it, all within one `retry` budget. With a `digest` from the API, the
+/// download must match it.
+async fn fetch_to_temp_file(
+    url: &str,
+    token: &str,
+    accept: Option<&str>,
+    digest: Option<&str>,
+    retry: &config::Retry,
+) -> anyhow::Result<File> {
     let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
-    while let Some(chunk) = response.chunk().await? {
-        file.write_all(&chunk).await?;
+    let mut hasher = Sha256::new();
+    let mut written: u64 = 0;
+    let mut attempt = 1;
+    loop {
+        let response = send_counting_attempts(retry, &mut attempt, || {
+            let mut request = CLIENT.get(url).bearer_auth(token);
+            if let Some(accept) = accept {
+                request = request.header(reqwest::header::ACCEPT, accept);
+            }
+            if written > 0 {
+                request = request.header(reqwest::header::RANGE, format!("bytes={written}-"));
+            }
+            request
+        })
+        .await?;
+
+        if written > 0 {
+            let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+            let start = resumed.then(|| content_range_start(&response)).flatten();
+            if start != Some(written) {
+                // The server ignored the range, or answered another one: start over.
+                file.set_len(0).await?;
+                file.rewind().await?;
+                hasher = Sha256::new();
+                written = 0;
+                if resumed {
+                    ensure!(attempt < retry.max_attempts, "download can't be resumed: the server sent a range other than the one asked for");
+                    attempt += 1;
+                    continue;
+                }
+            }
+        }
+
+        match stream_body(response, &mut file, &mut hasher, &mut written).await? {
+            Ok(()) => break,
+            Err(e) if attempt < retry.max_attempts => {
+                let e = e.without_url();
+                eprintln!("! Warning: download interrupted after {} bytes ({}); resuming", written, e);
+                tokio::time::sleep(backoff(retry, attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(anyhow::Error::new(e.without_url()).context(format!("download interrupted after {written} bytes"))),
+        }
+    }
+
+    match digest.map(|digest| digest.split_once(':')) {
+        None => {}
+        Some(Some(("sha256", expected))) => {
+            let actual = hex::encode(hasher.finalize());
+            ensure!(actual.eq_ignore_ascii_case(expected), "digest mismatch: expected sha256:{expected}, got sha256:{actual}");
+        }
+        Some(_) => eprintln!("! Warning: not verifying unsupported digest {:?}", digest.unwrap_or_default()),
     }
 
     let mut file = file.into_std().await;
@@ -110,10 +395,55 @@ async fn fetch_to_temp_file(url: &str, token: &str) -> anyhow::Result<File> {
     Ok(file)
 }
 
+/// Where a `206 Partial Content` body starts, from `Content-Range: bytes START-END/TOTAL`.
+fn content_range_start(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(reqwest::header::CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.trim().strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Appends the body to `file`, keeping `hasher` and `written` in step with what made
+/// it to disk so an interrupted download can resume where it stopped. The inner
+/// result is the network side, worth resuming; a local write failure is not.
+async fn stream_body(
+    mut response: reqwest::Response,
+    file: &mut tokio::fs::File,
+    hasher: &mut Sha256,
+    written: &mut u64,
+) -> anyhow::Result<reqwest::Result<()>> {
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return Ok(Ok(())),
+            Err(e) => return Ok(Err(e)),
+        };
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.context("failed to write the download to a temp file")?;
+        *written += chunk.len() as u64;
+    }
+}
+
 /// Extracts into a staging directory next to `target`, then swaps it in. The live
-/// directory is never unzipped over: a failed download or extraction leaves it
+/// directory is never extracted over: a failed download or extraction leaves it
 /// untouched, and files removed upstream don't linger from previous deploys.
-fn deploy_zip(zip_file: File, target: &Path, preserve: &[String]) -> anyhow::Result<()> {
+fn deploy_archive(archive: File, target: &Path, preserve: &[String], extract: &Extract) -> anyhow::Result<()> {
+    let (parent, name) = split_target(target)?;
+
+    // Staging lives next to the target so the swap renames stay on one filesystem.
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let staging = parent.join(format!(".{name}.new-{millis}"));
+    let old = next_old_path(parent, &name, millis);
+
+    extract_to(archive, &staging, extract)
+        .and_then(|()| swap_dirs(&staging, target, &old, preserve))
+        .inspect_err(|_| {
+            let _ = fs::remove_dir_all(&staging);
+        })
+}
+
+/// Splits `target` into its (created if missing) parent directory and its name; every
+/// sibling we create next to the target is named after it.
+fn split_target(target: &Path) -> anyhow::Result<(&Path, String)> {
     let parent = target
         .parent()
         .filter(|p| !p.as_os_str().is_empty())
@@ -121,54 +451,773 @@ fn deploy_zip(zip_file: File, target: &Path, preserve: &[String]) -> anyhow::Res
     let name = target
         .file_name()
         .with_context(|| format!("target {} has no directory name", target.display()))?
-        .to_string_lossy();
+        .to_string_lossy()
+        .into_owned();
 
     fs::create_dir_all(parent)?;
+    Ok((parent, name))
+}
+
+/// Release layout: extracts into `<target>.releases/<release>` and makes `target` a
+/// symlink to it, flipped with a single atomic rename so the live path never goes
+/// missing. Previous releases stay on disk; all but the newest `keep` are pruned.
+fn deploy_release(
+    archive: File,
+    target: &Path,
+    release: &str,
+    preserve: &[String],
+    keep: usize,
+    extract: &Extract,
+) -> anyhow::Result<()> {
+    let (parent, name) = split_target(target)?;
+    let releases = parent.join(format!("{name}.releases"));
+    fs::create_dir_all(&releases)?;
 
-    // Staging lives next to the target so the swap renames stay on one filesystem.
     let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-    let staging = parent.join(format!(".{name}.new-{millis}"));
-    let old = parent.join(format!(".{name}.old-{millis}"));
+    let staging = releases.join(format!(".{release}.new-{millis}"));
+    extract_to(archive, &staging, extract).inspect_err(|_| {
+        let _ = fs::remove_dir_all(&staging);
+    })?;
 
-    unzip_to(zip_file, &staging)
-        .and_then(|()| swap_dirs(&staging, target, &old, preserve))
-        .inspect_err(|_| {
-            let _ = fs::remove_dir_all(&staging);
-        })
+    // Redeploying a run that still has a release on disk must not clobber it: that
+    // directory may well be the live one.
+    let mut dest_name = release.to_string();
+    if releases.join(&dest_name).exists() {
+        dest_name = format!("{release}-{millis}");
+    }
+    let dest = releases.join(&dest_name);
+    fs::rename(&staging, &dest).inspect_err(|_| {
+        let _ = fs::remove_dir_all(&staging);
+    })?;
+
+    let (previous, migrated) = match current_release(target, &releases, millis)? {
+        Some((path, migrated)) => (Some(path), migrated),
+        None => (None, false),
+    };
+
+    if let Err(e) = flip_current(parent, &name, &dest_name, millis) {
+        let mut err = e.context("failed to flip the current symlink");
+        if let (true, Some(previous)) = (migrated, &previous) {
+            if let Err(e2) = fs::rename(previous, target) {
+                err = err.context(format!("RESTORE FAILED ({e2}), previous version left at {}", previous.display()));
+            }
+        }
+        return Err(err);
+    }
+
+    if let Some(previous) = &previous {
+        carry_preserved(previous, &dest, preserve).with_context(|| {
+            format!(
+                "deployed, but carrying preserved paths failed; previous release kept at {}",
+                previous.display()
+            )
+        })?;
+    }
+
+    if let Err(e) = prune_releases(&releases, &dest, keep) {
+        eprintln!("! Warning: failed to prune old releases in {}: {:#}", releases.display(), e);
+    }
+    Ok(())
+}
+
+/// The release directory `target` currently points at, if any. A plain directory left
+/// by the non-release layout is first moved into `releases` so it is kept like any
+/// other release (flagged `true`); that one-time migration briefly leaves `target`
+/// missing.
+fn current_release(target: &Path, releases: &Path, millis: u128) -> anyhow::Result<Option<(PathBuf, bool)>> {
+    let Ok(meta) = fs::symlink_metadata(target) else {
+        return Ok(None);
+    };
+
+    if meta.is_symlink() {
+        let link = fs::read_link(target)?;
+        let parent = target.parent().unwrap_or(Path::new(""));
+        return Ok(Some((parent.join(link), false)));
+    }
+
+    let migrated = releases.join(format!("migrated-{millis}"));
+    fs::rename(target, &migrated)
+        .with_context(|| format!("failed to move existing {} into the release layout", target.display()))?;
+    Ok(Some((migrated, true)))
+}
+
+/// Points the `target` symlink (`<parent>/<name>`) at release `release`: a fresh link is
+/// renamed over the old one, so the switch is atomic. The release's mtime is bumped,
+/// making "most recently modified" mean "most recently live" for pruning and rollback.
+fn flip_current(parent: &Path, name: &str, release: &str, millis: u128) -> anyhow::Result<()> {
+    // Relative, so the whole tree can be moved or bind-mounted elsewhere.
+    let link = parent.join(format!(".{name}.current-{millis}"));
+    std::os::unix::fs::symlink(Path::new(&format!("{name}.releases")).join(release), &link)?;
+    if let Err(e) = fs::rename(&link, parent.join(name)) {
+        let _ = fs::remove_file(&link);
+        return Err(e.into());
+    }
+
+    let release_dir = parent.join(format!("{name}.releases")).join(release);
+    if let Err(e) = File::open(&release_dir).and_then(|dir| dir.set_modified(SystemTime::now())) {
+        eprintln!("! Warning: failed to touch {}: {}", release_dir.display(), e);
+    }
+    Ok(())
+}
+
+/// Releases in `releases` other than `current`, most recently live first.
+fn other_releases(releases: &Path, current: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut candidates = Vec::new();
+    for entry in fs::read_dir(releases)? {
+        let entry = entry?;
+        let path = entry.path();
+        // Dot-names are in-progress staging directories of a concurrent deploy.
+        if path == current || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        candidates.push((entry.metadata()?.modified()?, path));
+    }
+
+    candidates.sort_by(|a, b| b.cmp(a));
+    Ok(candidates.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Removes all but the `keep` most recently live releases; `current` always counts as
+/// one of them.
+fn prune_releases(releases: &Path, current: &Path, keep: usize) -> anyhow::Result<()> {
+    for path in other_releases(releases, current)?.into_iter().skip(keep.saturating_sub(1)) {
+        fs::remove_dir_all(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Swaps `artifact`'s target back to an earlier version, re-carrying its `preserve`
+/// paths from the version being replaced, and returns the name of the version now
+/// live. With the release layout `to` names a release directory; without it only the
+/// single previous version is kept, so `to` must be unset.
+pub fn rollback(artifact: &config::Artifact, to: Option<&str>) -> anyhow::Result<String> {
+    ensure!(
+        !artifact.target.contains('{'),
+        "cannot roll back the templated target {}: roll back each deployed directory by hand",
+        artifact.target
+    );
+    let target = Path::new(&artifact.target);
+    if artifact.keep_releases.is_some() {
+        return rollback_release(target, to, &artifact.preserve);
+    }
+
+    if let Some(to) = to {
+        bail!("cannot roll {} back to {to:?}: named versions need `keep_releases`", target.display());
+    }
+    let (parent, name) = split_target(target)?;
+    let previous = previous_versions(parent, &name)?
+        .into_iter()
+        .next()
+        .with_context(|| format!("no previous version of {} is kept", target.display()))?;
+
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let old = next_old_path(parent, &name, millis);
+    swap_dirs(&previous, target, &old, &artifact.preserve)?;
+    Ok("previous".to_string())
+}
+
+/// Deletes a deployed `target` with everything kept next to it: previous versions,
+/// leftover staging directories and, in the release layout, its releases. Returns
+/// whether there was anything to delete.
+pub fn remove_target(target: &Path) -> anyhow::Result<bool> {
+    let (parent, name) = split_target(target)?;
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mut existed = false;
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let ours = file_name == name
+            || file_name == format!("{name}.releases")
+            || file_name.starts_with(&format!(".{name}.old-"))
+            || file_name.starts_with(&format!(".{name}.new-"))
+            || file_name.starts_with(&format!(".{name}.current-"));
+        if !ours {
+            continue;
+        }
+        existed = true;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(existed)
+}
+
+fn rollback_release(target: &Path, to: Option<&str>, preserve: &[String]) -> anyhow::Result<String> {
+    let (parent, name) = split_target(target)?;
+    let releases = parent.join(format!("{name}.releases"));
+
+    let link = fs::read_link(target)
+        .with_context(|| format!("{} is not a release symlink", target.display()))?;
+    let current = parent.join(link);
+
+    let chosen = match to {
+        Some(to) => {
+            let is_plain_name = Path::new(to).components().count() == 1
+                && matches!(Path::new(to).components().next(), Some(Component::Normal(_)));
+            ensure!(is_plain_name && !to.starts_with('.'), "invalid release name {to:?}");
+            let chosen = releases.join(to);
+            ensure!(chosen.is_dir(), "no release named {to:?} in {}", releases.display());
+            ensure!(chosen != current, "release {to:?} is already live");
+            chosen
+        }
+        None => other_releases(&releases, &current)?
+            .into_iter()
+            .next()
+            .with_context(|| format!("no previous release of {} is kept", target.display()))?,
+    };
+    let chosen_name = chosen
+        .file_name()
+        .context("release has no name")?
+        .to_string_lossy()
+        .into_owned();
+
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    flip_current(parent, &name, &chosen_name, millis)?;
+    carry_preserved(&current, &chosen, preserve).with_context(|| {
+        format!(
+            "rolled back, but carrying preserved paths failed; they remain in {}",
+            current.display()
+        )
+    })?;
+    Ok(chosen_name)
+}
+
+/// Cleans up after a deploy to `target` cut short by a crash or restart: removes
+/// staging directories and half-made symlinks, and, if the process died between
+/// the two renames of a swap, renames the previous version back into place. If it
+/// died after them (or, with the release layout, after the flip), while carrying
+/// `preserve` paths over, the rest are carried now: the redeploy would otherwise
+/// delete the previous version still holding them.
+pub fn clean_interrupted(target: &Path, preserve: &[String]) -> anyhow::Result<()> {
+    let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+        return Ok(());
+    };
+    let name = name.to_string_lossy();
+    if !parent.is_dir() {
+        return Ok(());
+    }
+
+    let (staging, link) = (format!(".{name}.new-"), format!(".{name}.current-"));
+    remove_debris(parent, |file_name| file_name.starts_with(&staging) || file_name.starts_with(&link))?;
+    // `<target>.releases/.<release>.new-<millis>`.
+    remove_debris(&parent.join(format!("{name}.releases")), |file_name| {
+        file_name.starts_with('.') && file_name.contains(".new-")
+    })?;
+
+    let releases = parent.join(format!("{name}.releases"));
+    if fs::symlink_metadata(target).is_ok_and(|meta| meta.is_symlink()) && releases.is_dir() {
+        return finish_release_carry(target, &releases, preserve);
+    }
+
+    let Some(previous) = previous_versions(parent, &name)?.into_iter().next() else {
+        return Ok(());
+    };
+    match fs::symlink_metadata(target) {
+        Err(_) => {
+            fs::rename(&previous, target)?;
+            println!("> Restored {} from {} after an interrupted swap.", target.display(), previous.display());
+        }
+        // A completed swap leaves no preserved path behind in the previous version.
+        Ok(meta) if meta.is_dir() && preserve.iter().any(|rel| previous.join(rel).exists()) => {
+            carry_preserved(&previous, target, preserve)?;
+            println!("> Carried the preserved paths left in {} into {}.", previous.display(), target.display());
+        }
+        Ok(_) => {}
+    }
+    Ok(())
+}
+
+/// `deploy_release` flips `target` before carrying the preserved paths over: a deploy
+/// cut short in between leaves them in the release live before, the most recently
+/// live of the others. A completed carry leaves none behind there.
+fn finish_release_carry(target: &Path, releases: &Path, preserve: &[String]) -> anyhow::Result<()> {
+    let Some(current) = fs::read_link(target)?.file_name().map(|release| releases.join(release)) else {
+        return Ok(());
+    };
+    let Some(previous) = other_releases(releases, &current)?.into_iter().next() else {
+        return Ok(());
+    };
+    if preserve.iter().any(|rel| previous.join(rel).exists()) {
+        carry_preserved(&previous, &current, preserve)?;
+        println!("> Carried the preserved paths left in {} into {}.", previous.display(), current.display());
+    }
+    Ok(())
+}
+
+/// Removes the entries of `dir` (if it exists) whose names satisfy `is_debris`.
+fn remove_debris(dir: &Path, is_debris: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !is_debris(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+        println!("> Removed {} left by an interrupted deploy.", entry.path().display());
+    }
+    Ok(())
+}
+
+/// Where the live version goes when replaced. Kept versions are ordered by their
+/// suffix, so one created within the same millisecond as an existing one bumps it.
+fn next_old_path(parent: &Path, name: &str, mut millis: u128) -> PathBuf {
+    loop {
+        let old = parent.join(format!(".{name}.old-{millis}"));
+        if !old.exists() {
+            return old;
+        }
+        millis += 1;
+    }
+}
+
+/// Previous versions kept by the plain layout (`.<name>.old-<millis>`), newest first.
+fn previous_versions(parent: &Path, name: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let prefix = format!(".{name}.old-");
+    let mut versions = Vec::new();
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(millis) = file_name.to_str().and_then(|n| n.strip_prefix(&prefix)) else {
+            continue;
+        };
+        if let Ok(millis) = millis.parse::<u128>() {
+            versions.push((millis, entry.path()));
+        }
+    }
+
+    versions.sort_by(|a, b| b.cmp(a));
+    Ok(versions.into_iter().map(|(_, path)| path).collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+/// Tells the archive formats apart by their magic bytes; file names (artifact and
+/// asset names) are not to be trusted to match the contents.
+fn sniff_format(archive: &mut File) -> anyhow::Result<Format> {
+    let mut head = [0; 262];
+    let mut len = 0;
+    while len < head.len() {
+        match archive.read(&mut head[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    archive.rewind()?;
+
+    let head = &head[..len];
+    Ok(if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        Format::Zip
+    } else if head.starts_with(&[0x1f, 0x8b]) {
+        Format::TarGz
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Format::TarZst
+    } else if head.get(257..262) == Some(b"ustar") {
+        Format::Tar
+    } else {
+        bail!("not a zip, tar, tar.gz or tar.zst archive");
+    })
+}
+
+/// Per-artifact extraction settings, from `config::Artifact`.
+#[derive(Debug, Clone, Default)]
+struct Extract {
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    max_unpacked_bytes: Option<u64>,
+    max_entries: Option<u64>,
+    max_compression_ratio: Option<u64>,
+    max_path_depth: Option<usize>,
+    checksums: Option<String>,
+    signing_key: Option<String>,
+    /// Size of the download the archive was unwrapped from (`unwrap_inner_archive`):
+    /// `max_compression_ratio` holds against what was downloaded, not against the
+    /// inner archive, or nesting would square it.
+    downloaded_len: Option<u64>,
+}
+
+impl From<&config::Artifact> for Extract {
+    fn from(artifact: &config::Artifact) -> Self {
+        Self {
+            file_mode: artifact.file_mode,
+            dir_mode: artifact.dir_mode,
+            max_unpacked_bytes: artifact.max_unpacked_bytes,
+            max_entries: artifact.max_entries,
+            max_compression_ratio: artifact.max_compression_ratio,
+            max_path_depth: artifact.max_path_depth,
+            checksums: artifact.checksums.clone(),
+            signing_key: artifact.signing_key.clone(),
+            downloaded_len: None,
+        }
+    }
 }
 
-fn unzip_to(zip_file: File, staging: &Path) -> anyhow::Result<()> {
+/// What an extraction may still write under the artifact's limits, charged entry by
+/// entry as it streams. The compression ratio is held against the whole archive, so
+/// it simply caps the bytes unpacked from it.
+struct Budget<'a> {
+    extract: &'a Extract,
+    bytes_left: u64,
+    /// The limit `bytes_left` comes from, for the error.
+    byte_limit: &'static str,
+    entries: u64,
+}
+
+impl<'a> Budget<'a> {
+    fn new(extract: &'a Extract, archive_len: u64) -> Self {
+        let by_ratio = extract.max_compression_ratio.map(|ratio| ratio.saturating_mul(archive_len));
+        let (bytes_left, byte_limit) = match (extract.max_unpacked_bytes, by_ratio) {
+            (Some(bytes), Some(ratio)) if ratio < bytes => (ratio, "max_compression_ratio"),
+            (Some(bytes), _) => (bytes, "max_unpacked_bytes"),
+            (None, Some(ratio)) => (ratio, "max_compression_ratio"),
+            (None, None) => (u64::MAX, ""),
+        };
+        Self { extract, bytes_left, byte_limit, entries: 0 }
+    }
+
+    /// Checks the entry count an archive declares up front (a zip's central
+    /// directory), before anything is extracted.
+    fn listed(&self, entries: usize) -> anyhow::Result<()> {
+        if let Some(max) = self.extract.max_entries {
+            ensure!(entries as u64 <= max, "archive lists {entries} entries, over max_entries ({max})");
+        }
+        Ok(())
+    }
+
+    /// Charges one entry at `relative`.
+    fn entry(&mut self, relative: &Path) -> anyhow::Result<()> {
+        self.entries += 1;
+        if let Some(max) = self.extract.max_entries {
+            ensure!(self.entries <= max, "archive exceeds max_entries ({max})");
+        }
+        if let Some(max) = self.extract.max_path_depth {
+            let depth = relative.components().count();
+            ensure!(depth <= max, "archive entry {} exceeds max_path_depth ({max})", relative.display());
+        }
+        Ok(())
+    }
+
+    fn bytes(&mut self, len: u64) -> anyhow::Result<()> {
+        match self.bytes_left.checked_sub(len) {
+            Some(left) => self.bytes_left = left,
+            None => bail!("archive exceeds {} when unpacked", self.byte_limit),
+        }
+        Ok(())
+    }
+
+    /// Copies `reader` into `writer`, charging the bytes as they go and stopping as
+    /// soon as the budget runs out: declared entry sizes can't be trusted.
+    fn copy(&mut self, reader: &mut impl Read, writer: &mut impl io::Write) -> anyhow::Result<()> {
+        let copied = io::copy(&mut reader.take(self.bytes_left.saturating_add(1)), writer)?;
+        self.bytes(copied)
+    }
+}
+
+/// Extracts a zip archive or a (plain, gzipped or zstd-compressed) tarball into
+/// `staging`, which nothing may escape, then applies the `file_mode` / `dir_mode`
+/// overrides and verifies the `checksums` file and its signature.
+fn extract_to(mut archive: File, staging: &Path, extract: &Extract) -> anyhow::Result<()> {
+    let archive_len = match extract.downloaded_len {
+        Some(len) => len,
+        None => archive.metadata()?.len(),
+    };
+    let mut budget = Budget::new(extract, archive_len);
+    match sniff_format(&mut archive)? {
+        Format::Zip => unzip_to(archive, staging, &mut budget)?,
+        Format::Tar => untar_to(archive, staging, &mut budget)?,
+        Format::TarGz => untar_to(flate2::read::GzDecoder::new(archive), staging, &mut budget)?,
+        Format::TarZst => untar_to(zstd::Decoder::new(archive)?, staging, &mut budget)?,
+    }
+    if extract.file_mode.is_some() || extract.dir_mode.is_some() {
+        override_modes(staging, extract)?;
+    }
+    if let Some(checksums) = &extract.checksums {
+        if let Some(key) = &extract.signing_key {
+            verify_signature(staging, checksums, key).with_context(|| format!("signature verification of {checksums} failed"))?;
+        }
+        verify_checksums(staging, checksums).with_context(|| format!("checksum verification against {checksums} failed"))?;
+    }
+    Ok(())
+}
+
+/// Checks `<checksums>.minisig`, a minisign signature over the `checksums` file, against
+/// `key`. Offline: the key comes from the config, never from GitHub, so a stolen
+/// token or a tampered workflow can't produce an artifact that passes.
+fn verify_signature(staging: &Path, checksums: &str, key: &str) -> anyhow::Result<()> {
+    let key = minisign_verify::PublicKey::from_base64(key).context("invalid signing_key")?;
+    let signature_path = staging.join(format!("{checksums}.minisig"));
+    let signature = fs::read_to_string(&signature_path).context("signature file missing or unreadable")?;
+    let signature = minisign_verify::Signature::decode(&signature).context("malformed signature file")?;
+    let signed = fs::read(staging.join(checksums)).context("checksums file missing or unreadable")?;
+    // Legacy (non-prehashed) signatures are as sound for a small manifest.
+    key.verify(&signed, &signature, true).context("bad signature")?;
+    Ok(())
+}
+
+/// Checks every regular file extracted into `staging` against the `sha256sum`-style
+/// `checksums` file among them: each must be listed with a matching hash, and each
+/// listed file must exist. A manifest can't vouch for symlinks, so there must be
+/// none: an added one could alias a served path to anything else in the tree.
+fn verify_checksums(staging: &Path, checksums: &str) -> anyhow::Result<()> {
+    let listing = fs::read_to_string(staging.join(checksums)).context("checksums file missing or unreadable")?;
+    let mut expected = HashMap::new();
+    for line in listing.lines().filter(|line| !line.trim().is_empty()) {
+        // `<hex>  <path>`, or `<hex> *<path>` for binary mode.
+        let (hash, path) = line.split_once(' ').with_context(|| format!("malformed line {line:?}"))?;
+        let path = path.strip_prefix([' ', '*']).unwrap_or(path);
+        expected.insert(path.strip_prefix("./").unwrap_or(path).to_string(), hash.to_ascii_lowercase());
+    }
+
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(staging.join(&dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                dirs.push(relative);
+                continue;
+            }
+            let name = relative.to_string_lossy();
+            ensure!(kind.is_file(), "{name} is a symlink or special file, which checksums can't cover");
+            if name == checksums || name == format!("{checksums}.minisig") {
+                continue;
+            }
+            let want = expected.remove(name.as_ref()).with_context(|| format!("{name} is not listed"))?;
+            let actual = sha256_file(&entry.path())?;
+            ensure!(actual == want, "{name} has sha256 {actual}, expected {want}");
+        }
+    }
+
+    let mut unseen: Vec<String> = expected.into_keys().collect();
+    unseen.sort();
+    ensure!(unseen.is_empty(), "listed but not in the artifact: {}", unseen.join(", "));
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// The single file inside the zip `outer`, copied out to an unnamed temp file. Its
+/// name never touches the filesystem, so it needs no containment check of its own;
+/// its contents go through `extract_to` like any download. The unpacking limits
+/// apply to the copy too.
+fn inner_archive(outer: File, extract: &Extract) -> anyhow::Result<File> {
+    let mut budget = Budget::new(extract, outer.metadata()?.len());
+    let mut zip = zip::ZipArchive::new(outer).context("unwrap_inner_archive is set, but the artifact is not a zip")?;
+    let files: Vec<usize> = (0..zip.len())
+        .filter(|&i| zip.by_index(i).is_ok_and(|file| !file.is_dir()))
+        .collect();
+    let [index] = files[..] else {
+        bail!("unwrap_inner_archive is set, but the artifact holds {} files instead of one", files.len());
+    };
+
+    let mut inner = tempfile::tempfile()?;
+    budget.copy(&mut zip.by_index(index)?, &mut inner)?;
+    inner.rewind()?;
+    Ok(inner)
+}
+
+fn untar_to(reader: impl Read, staging: &Path, budget: &mut Budget) -> anyhow::Result<()> {
+    fs::create_dir_all(staging)?;
+
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        // `unpack_in` skips `..` traversal and strips leading `/` (tar-slip); check the
+        // same relative path it will write.
+        if path.components().any(|c| c == Component::ParentDir) {
+            continue;
+        }
+        let relative: PathBuf = path.components().filter(|c| matches!(c, Component::Normal(_))).collect();
+        budget.entry(&relative)?;
+
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() {
+            let link_target = entry.link_name()?.context("symlink entry without a target")?;
+            ensure!(
+                link_stays_inside(&relative, &link_target),
+                "symlink {} -> {} points outside the archive",
+                path.display(),
+                link_target.display()
+            );
+        } else if !kind.is_file() && !kind.is_dir() && !kind.is_hard_link() {
+            eprintln!("! Warning: skipping special tar entry {}", path.display());
+            continue;
+        }
+        ensure_no_symlink_ancestors(staging, &relative)?;
+        // Regular files unpack exactly their header's size (sparse ones are skipped
+        // above), so they can be charged before anything is written.
+        if kind.is_file() {
+            budget.bytes(entry.size())?;
+        }
+        // Modes come from the header, minus setuid/setgid/sticky bits. Hard links are
+        // checked by `unpack_in` to point inside `staging`.
+        entry.unpack_in(staging)?;
+    }
+
+    Ok(())
+}
+
+fn unzip_to(zip_file: File, staging: &Path, budget: &mut Budget) -> anyhow::Result<()> {
     fs::create_dir_all(staging)?;
 
     let mut archive = zip::ZipArchive::new(zip_file)?;
+    budget.listed(archive.len())?;
+    // Applied last, so a read-only directory doesn't block its own entries.
+    let mut dir_modes = Vec::new();
     for i in 0..archive.len() {
         let mut file = archive.by_index(i)?;
         // `enclosed_name` rejects absolute paths and `..` traversal (zip-slip).
         let Some(enclosed) = file.enclosed_name() else {
             continue;
         };
-        let out_path = staging.join(enclosed);
+        budget.entry(&enclosed)?;
+        ensure_no_symlink_ancestors(staging, &enclosed)?;
+        let out_path = staging.join(&enclosed);
+        // Unix mode of entries zipped on Unix, minus setuid/setgid/sticky bits.
+        let mode = file.unix_mode().map(|mode| mode & 0o777);
 
         if file.is_dir() {
             fs::create_dir_all(&out_path)?;
-        } else {
-            if let Some(parent) = out_path.parent() {
-                fs::create_dir_all(parent)?;
+            if let Some(mode) = mode {
+                dir_modes.push((out_path, mode));
             }
+            continue;
+        }
+
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // A duplicate entry replaces the earlier one instead of writing through it.
+        if out_path.symlink_metadata().is_ok_and(|meta| !meta.is_dir()) {
+            fs::remove_file(&out_path)?;
+        }
 
+        if file.is_symlink() {
+            let mut link_target = Vec::new();
+            budget.copy(&mut file, &mut link_target)?;
+            let link_target = String::from_utf8(link_target).context("symlink target is not UTF-8")?;
+            ensure!(
+                link_stays_inside(&enclosed, Path::new(&link_target)),
+                "symlink {} -> {} points outside the archive",
+                enclosed.display(),
+                link_target
+            );
+            symlink(&link_target, &out_path)?;
+        } else {
             let mut out_file = File::create(&out_path)?;
-            io::copy(&mut file, &mut out_file)?;
+            budget.copy(&mut file, &mut out_file)?;
+            if let Some(mode) = mode {
+                out_file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
         }
     }
 
+    for (path, mode) in dir_modes.into_iter().rev() {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Whether a symlink at `link` (relative to the extraction root) to `target` stays
+/// inside the root, whatever the archive's other links point to. `target` must be
+/// relative with `..` only leading, never climbing above the root: a `..` after a
+/// component could back out of a link into its real parent. This holds because no
+/// entry is ever written through a link (`ensure_no_symlink_ancestors`).
+fn link_stays_inside(link: &Path, target: &Path) -> bool {
+    let mut depth = link.parent().map_or(0, |parent| parent.components().count());
+    let mut leading = true;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if leading => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return false,
+            },
+            Component::Normal(_) => leading = false,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Fails if an entry at `relative` would be written through a symlink extracted
+/// earlier, i.e. if one of its parent directories is a link.
+fn ensure_no_symlink_ancestors(staging: &Path, relative: &Path) -> anyhow::Result<()> {
+    let mut path = staging.to_path_buf();
+    for ancestor in relative.parent().into_iter().flat_map(Path::components) {
+        path.push(ancestor);
+        if path.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink()) {
+            bail!("archive entry {} lies behind a symlink", relative.display());
+        }
+    }
+    Ok(())
+}
+
+/// Sets the mode of every file and directory under `staging` (never following links)
+/// to the artifact's `file_mode` / `dir_mode`, where set. Directories are done last,
+/// deepest first, so a read-only one doesn't block its own contents.
+fn override_modes(staging: &Path, extract: &Extract) -> anyhow::Result<()> {
+    let mut dirs = vec![staging.to_path_buf()];
+    let mut i = 0;
+    while let Some(dir) = dirs.get(i).cloned() {
+        i += 1;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                dirs.push(entry.path());
+            } else if let (true, Some(mode)) = (kind.is_file(), extract.file_mode) {
+                fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+
+    if let Some(mode) = extract.dir_mode {
+        // `staging` itself becomes the target directory.
+        for dir in dirs.iter().rev() {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+        }
+    }
     Ok(())
 }
 
 /// Replaces `target` with `staging`: rename the live directory to `old`, rename
-/// `staging` into place, carry `preserve` paths over from `old`, then delete `old`.
-/// Not a single atomic step, but the vulnerable window is a few renames instead of
-/// the whole extraction — on swap failure the previous version is renamed back, and
-/// on carry failure `old` is kept on disk so no preserved state is ever lost.
+/// `staging` into place, then carry `preserve` paths over from `old`. `old` stays on
+/// disk as the previous version (for rollback); any older ones are deleted. Not a
+/// single atomic step, but the vulnerable window is a few renames instead of the whole
+/// extraction — on swap failure the previous version is renamed back.
 fn swap_dirs(staging: &Path, target: &Path, old: &Path, preserve: &[String]) -> anyhow::Result<()> {
     if !target.exists() {
         // First deploy: nothing to carry; artifact-shipped copies of preserved
@@ -195,9 +1244,18 @@ fn swap_dirs(staging: &Path, target: &Path, old: &Path, preserve: &[String]) ->
         )
     })?;
 
-    if let Err(e) = fs::remove_dir_all(old) {
-        // The new version is live; a leftover old tree is cosmetic. Don't fail the deploy.
-        eprintln!("! Warning: failed to remove previous version at {}: {}", old.display(), e);
+    // The new version is live; a leftover older tree is cosmetic. Don't fail the deploy.
+    if let (Some(parent), Some(name)) = (target.parent(), target.file_name()) {
+        match previous_versions(parent, &name.to_string_lossy()) {
+            Ok(versions) => {
+                for version in versions.iter().filter(|v| v.as_path() != old) {
+                    if let Err(e) = fs::remove_dir_all(version) {
+                        eprintln!("! Warning: failed to remove old version at {}: {}", version.display(), e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("! Warning: failed to list old versions of {}: {:#}", target.display(), e),
+        }
     }
     Ok(())
 }
@@ -230,25 +1288,84 @@ fn carry_preserved(old: &Path, target: &Path, preserve: &[String]) -> anyhow::Re
 mod tests {
     use super::*;
     use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Builds a zip archive in an unnamed temp file, rewound and ready to read.
+    /// `Some(contents)` adds a file entry, `None` a directory entry.
+    fn build_zip(entries: &[(&str, Option<&str>)]) -> File {
+        let mut writer = zip::ZipWriter::new(tempfile::tempfile().unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for &(name, contents) in entries {
+            match contents {
+                // `start_file` (unlike `start_file_from_path`) keeps the raw name, so
+                // hostile entries like `../evil.txt` reach the extractor unsanitized.
+                Some(contents) => {
+                    writer.start_file(name, options).unwrap();
+                    writer.write_all(contents.as_bytes()).unwrap();
+                }
+                None => {
+                    writer.add_directory(name, options).unwrap();
+                }
+            }
+        }
+        let mut file = writer.finish().unwrap();
+        file.rewind().unwrap();
+        file
+    }
 
-    /// Builds a zip archive in an unnamed temp file, rewound and ready to read.
-    /// `Some(contents)` adds a file entry, `None` a directory entry.
-    fn build_zip(entries: &[(&str, Option<&str>)]) -> File {
-        let mut writer = zip::ZipWriter::new(tempfile::tempfile().unwrap());
-        let options = zip::write::SimpleFileOptions::default();
+    /// Builds a tarball like `build_zip`, compressed per `format`. `links` are
+    /// `(name, target)` symlink entries, added first; files named `*.sh` get mode
+    /// 0755, others 0644.
+    fn build_tar(entries: &[(&str, Option<&str>)], links: &[(&str, &str)], format: Format) -> File {
+        let mut builder = tar::Builder::new(Vec::new());
+        for &(name, target) in links {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, name, target).unwrap();
+        }
         for &(name, contents) in entries {
+            let mut header = tar::Header::new_gnu();
             match contents {
-                // `start_file` (unlike `start_file_from_path`) keeps the raw name, so
-                // hostile entries like `../evil.txt` reach the extractor unsanitized.
                 Some(contents) => {
-                    writer.start_file(name, options).unwrap();
-                    writer.write_all(contents.as_bytes()).unwrap();
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(contents.len() as u64);
+                    header.set_mode(if name.ends_with(".sh") { 0o755 } else { 0o644 });
+                    builder.append_data(&mut header, name, contents.as_bytes()).unwrap();
                 }
                 None => {
-                    writer.add_directory(name, options).unwrap();
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, name, io::empty()).unwrap();
                 }
             }
         }
+        let tar = builder.into_inner().unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        match format {
+            Format::Tar => file.write_all(&tar).unwrap(),
+            Format::TarGz => {
+                let mut gz = flate2::write::GzEncoder::new(&mut file, flate2::Compression::default());
+                gz.write_all(&tar).unwrap();
+                gz.finish().unwrap();
+            }
+            Format::TarZst => zstd::stream::copy_encode(&tar[..], &mut file, 0).unwrap(),
+            Format::Zip => unreachable!("use build_zip"),
+        }
+        file.rewind().unwrap();
+        file
+    }
+
+    /// A zip holding `inner` as its single file, `dist.tar.gz`.
+    fn wrap_in_zip(mut inner: File) -> File {
+        let mut bytes = Vec::new();
+        inner.read_to_end(&mut bytes).unwrap();
+        let mut writer = zip::ZipWriter::new(tempfile::tempfile().unwrap());
+        writer.start_file("dist.tar.gz", zip::write::SimpleFileOptions::default()).unwrap();
+        writer.write_all(&bytes).unwrap();
         let mut file = writer.finish().unwrap();
         file.rewind().unwrap();
         file
@@ -268,6 +1385,78 @@ mod tests {
         names
     }
 
+    #[test]
+    fn interrupted_swap_is_cleaned_up_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        for debris in [".app.old-1", ".app.old-2", ".app.new-3", "app.releases/.7.new-3", "app.releases/6"] {
+            fs::create_dir_all(dir.path().join(debris)).unwrap();
+        }
+        fs::write(dir.path().join(".app.old-2/v.txt"), "v2").unwrap();
+
+        // Killed between renaming the live version away and the staging one in.
+        clean_interrupted(&target, &[]).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("v.txt")).unwrap(), "v2");
+        assert!(dir.path().join(".app.old-1").exists());
+        assert!(!dir.path().join(".app.new-3").exists());
+        assert!(!dir.path().join("app.releases/.7.new-3").exists());
+        assert!(dir.path().join("app.releases/6").exists());
+
+        // Nothing to do for a target never deployed.
+        clean_interrupted(&dir.path().join("missing/app"), &[]).unwrap();
+    }
+
+    #[test]
+    fn interrupted_carry_of_preserved_paths_is_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let preserve = ["data".to_owned(), "uploads".to_owned()];
+        for path in ["app/data", "app/uploads", ".app.old-2/uploads"] {
+            fs::create_dir_all(dir.path().join(path)).unwrap();
+        }
+        fs::write(target.join("data/db"), "live").unwrap();
+        fs::write(target.join("uploads/seed.txt"), "shipped").unwrap();
+        fs::write(dir.path().join(".app.old-2/uploads/photo.jpg"), "live").unwrap();
+
+        // Killed after carrying `data`, before `uploads`.
+        clean_interrupted(&target, &preserve).unwrap();
+        assert_eq!(read_file(&target.join("data/db")), "live");
+        assert_eq!(dir_entry_names(&target.join("uploads")), ["photo.jpg"]);
+
+        // The redeploy carries them on, though it deletes that previous version.
+        let zip = build_zip(&[("index.html", Some("v3"))]);
+        deploy_archive(zip, &target, &preserve, &Extract::default()).unwrap();
+        assert_eq!(read_file(&target.join("data/db")), "live");
+        assert_eq!(read_file(&target.join("uploads/photo.jpg")), "live");
+        assert!(!dir.path().join(".app.old-2").exists());
+    }
+
+    #[test]
+    fn carry_cut_short_by_a_crash_after_the_flip_is_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let preserve = ["data".to_owned()];
+        let zip = build_zip(&[("index.html", Some("v1"))]);
+        deploy_release(zip, &target, "1", &preserve, 3, &Extract::default()).unwrap();
+        fs::create_dir_all(target.join("data")).unwrap();
+        fs::write(target.join("data/db"), "live").unwrap();
+
+        // Release 2 went live, and the process died before carrying `data` over.
+        let zip = build_zip(&[("index.html", Some("v2"))]);
+        extract_to(zip, &dir.path().join("app.releases/2"), &Extract::default()).unwrap();
+        flip_current(dir.path(), "app", "2", 0).unwrap();
+        assert!(!target.join("data").exists());
+
+        clean_interrupted(&target, &preserve).unwrap();
+        assert_eq!(read_file(&target.join("index.html")), "v2");
+        assert_eq!(read_file(&target.join("data/db")), "live");
+        assert!(!dir.path().join("app.releases/1/data").exists());
+        // Nothing is left to carry the next time.
+        clean_interrupted(&target, &preserve).unwrap();
+        assert_eq!(read_file(&target.join("data/db")), "live");
+    }
+
     #[test]
     fn fresh_deploy_creates_target_with_exact_zip_contents() {
         let dir = tempfile::tempdir().unwrap();
@@ -278,7 +1467,7 @@ mod tests {
             ("sub/inner.txt", Some("nested contents")),
         ]);
 
-        deploy_zip(zip, &target, &[]).unwrap();
+        deploy_archive(zip, &target, &[], &Extract::default()).unwrap();
 
         assert_eq!(read_file(&target.join("hello.txt")), "hello world");
         assert_eq!(read_file(&target.join("sub").join("inner.txt")), "nested contents");
@@ -286,6 +1475,516 @@ mod tests {
         assert_eq!(dir_entry_names(&target.join("sub")), ["inner.txt"]);
     }
 
+    fn mode_of(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn tarballs_deploy_like_zip() {
+        for format in [Format::Tar, Format::TarGz, Format::TarZst] {
+            let dir = tempfile::tempdir().unwrap();
+            let target = dir.path().join("app");
+            let archive = build_tar(
+                &[("hello.txt", Some("hello world")), ("sub", None), ("sub/inner.txt", Some("nested contents"))],
+                &[],
+                format,
+            );
+
+            deploy_archive(archive, &target, &[], &Extract::default()).unwrap();
+
+            assert_eq!(read_file(&target.join("hello.txt")), "hello world", "{format:?}");
+            assert_eq!(read_file(&target.join("sub").join("inner.txt")), "nested contents", "{format:?}");
+            assert_eq!(dir_entry_names(&target), ["hello.txt", "sub"], "{format:?}");
+        }
+    }
+
+    #[test]
+    fn tar_modes_and_inside_symlinks_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let archive = build_tar(
+            &[("bin", None), ("bin/run.sh", Some("#!/bin/sh")), ("lib", None), ("lib/data.txt", Some("data"))],
+            &[("current", "lib"), ("bin/data", "../lib/data.txt")],
+            Format::TarGz,
+        );
+
+        deploy_archive(archive, &target, &[], &Extract::default()).unwrap();
+
+        assert_eq!(mode_of(&target.join("bin/run.sh")), 0o755);
+        assert_eq!(mode_of(&target.join("lib/data.txt")), 0o644);
+        assert_eq!(fs::read_link(target.join("current")).unwrap(), Path::new("lib"));
+        assert_eq!(read_file(&target.join("bin/data")), "data");
+    }
+
+    #[test]
+    fn zip_modes_and_inside_symlinks_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let mut writer = zip::ZipWriter::new(tempfile::tempfile().unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("run.sh", options.unix_permissions(0o755)).unwrap();
+        writer.write_all(b"#!/bin/sh").unwrap();
+        writer.start_file("data.txt", options.unix_permissions(0o640)).unwrap();
+        writer.write_all(b"data").unwrap();
+        writer.add_symlink("latest", "data.txt", options).unwrap();
+        let mut zip = writer.finish().unwrap();
+        zip.rewind().unwrap();
+
+        deploy_archive(zip, &target, &[], &Extract::default()).unwrap();
+
+        assert_eq!(mode_of(&target.join("run.sh")), 0o755);
+        assert_eq!(mode_of(&target.join("data.txt")), 0o640);
+        assert_eq!(fs::read_link(target.join("latest")).unwrap(), Path::new("data.txt"));
+    }
+
+    #[test]
+    fn escaping_symlinks_are_rejected() {
+        let links: [&[(&str, &str)]; 3] = [&[("link", "/etc/passwd")], &[("sub/link", "../../x")], &[("link", "sub/../..")]];
+        for links in links {
+            let dir = tempfile::tempdir().unwrap();
+            let target = dir.path().join("app");
+            let archive = build_tar(&[("sub", None)], links, Format::Tar);
+            let err = deploy_archive(archive, &target, &[], &Extract::default()).unwrap_err();
+            assert!(err.to_string().contains("points outside"), "{links:?}: {err:#}");
+            assert_eq!(dir_entry_names(dir.path()), Vec::<String>::new(), "{links:?}: staging cleaned up");
+        }
+    }
+
+    #[test]
+    fn entries_are_never_written_through_symlinks() {
+        // `up` points at the staging root; were `up/x` allowed, `up/..` style tricks
+        // could follow. Any entry behind a link is refused.
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let archive = build_tar(&[("up/x.txt", Some("through a link"))], &[("up", ".")], Format::Tar);
+        let err = deploy_archive(archive, &target, &[], &Extract::default()).unwrap_err();
+        assert!(err.to_string().contains("behind a symlink"), "got: {err:#}");
+    }
+
+    #[test]
+    fn mode_overrides_apply_to_every_file_and_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let zip = build_zip(&[("sub", None), ("sub/a.txt", Some("a")), ("b.txt", Some("b"))]);
+        let extract = Extract { file_mode: Some(0o600), dir_mode: Some(0o750), ..Default::default() };
+
+        deploy_archive(zip, &target, &[], &extract).unwrap();
+
+        assert_eq!(mode_of(&target), 0o750);
+        assert_eq!(mode_of(&target.join("sub")), 0o750);
+        assert_eq!(mode_of(&target.join("sub/a.txt")), 0o600);
+        assert_eq!(mode_of(&target.join("b.txt")), 0o600);
+    }
+
+    #[test]
+    fn unknown_format_is_rejected() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"<html>not an archive</html>").unwrap();
+        file.rewind().unwrap();
+        let err = extract_to(file, &tempfile::tempdir().unwrap().path().join("staging"), &Extract::default()).unwrap_err();
+        assert!(err.to_string().contains("not a zip"), "got: {err:#}");
+    }
+
+    #[test]
+    fn inner_archive_is_unwrapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let outer = wrap_in_zip(build_tar(&[("hello.txt", Some("hello world"))], &[], Format::TarGz));
+
+        deploy_archive(inner_archive(outer, &Extract::default()).unwrap(), &target, &[], &Extract::default()).unwrap();
+
+        assert_eq!(dir_entry_names(&target), ["hello.txt"]);
+    }
+
+    #[test]
+    fn inner_archive_ratio_counts_against_the_download() {
+        let big = "x".repeat(100_000);
+        let inner = build_tar(&[("big.txt", Some(&big))], &[], Format::TarGz);
+        let inner_len = inner.metadata().unwrap().len();
+        let ratio = 100_000 / inner_len + 1;
+
+        let dir = tempfile::tempdir().unwrap();
+        let extract = Extract { max_compression_ratio: Some(ratio), ..Default::default() };
+        deploy_archive(inner.try_clone().unwrap(), &dir.path().join("app"), &[], &extract).unwrap();
+
+        // Unwrapped from a download half its size, the same archive is over the ratio.
+        let mut inner = inner;
+        inner.rewind().unwrap();
+        let extract = Extract { downloaded_len: Some(inner_len / 2), ..extract };
+        let err = limit_error(inner, extract);
+        assert!(err.contains("max_compression_ratio"), "got: {err}");
+    }
+
+    #[test]
+    fn inner_archive_needs_exactly_one_file() {
+        let outer = build_zip(&[("dir", None), ("a.tar", Some("a")), ("b.tar", Some("b"))]);
+        let err = inner_archive(outer, &Extract::default()).unwrap_err();
+        assert!(err.to_string().contains("holds 2 files"), "got: {err:#}");
+    }
+
+    fn limit_error(archive: File, extract: Extract) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let err = deploy_archive(archive, &target, &[], &extract).unwrap_err();
+        assert_eq!(dir_entry_names(dir.path()), Vec::<String>::new(), "staging cleaned up");
+        err.to_string()
+    }
+
+    #[test]
+    fn extraction_limits_abort_and_clean_up() {
+        let big = "x".repeat(100_000);
+        let zip = || build_zip(&[("a/b/c.txt", Some("deep")), ("big.txt", Some(&big))]);
+        let tar = || build_tar(&[("a/b/c.txt", Some("deep")), ("big.txt", Some(&big))], &[], Format::TarGz);
+
+        for (name, archive) in [("zip", &zip as &dyn Fn() -> File), ("tar.gz", &tar)] {
+            let err = limit_error(archive(), Extract { max_unpacked_bytes: Some(50_000), ..Default::default() });
+            assert!(err.contains("max_unpacked_bytes"), "{name}: {err}");
+
+            let err = limit_error(archive(), Extract { max_compression_ratio: Some(10), ..Default::default() });
+            assert!(err.contains("max_compression_ratio"), "{name}: {err}");
+
+            let err = limit_error(archive(), Extract { max_entries: Some(1), ..Default::default() });
+            assert!(err.contains("max_entries"), "{name}: {err}");
+
+            let err = limit_error(archive(), Extract { max_path_depth: Some(2), ..Default::default() });
+            assert!(err.contains("max_path_depth"), "{name}: {err}");
+        }
+        // A zip is refused on its central directory alone, before extracting anything.
+        let err = limit_error(zip(), Extract { max_entries: Some(1), ..Default::default() });
+        assert!(err.contains("lists 2 entries, over max_entries (1)"), "{err}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let within = Extract {
+            max_unpacked_bytes: Some(200_000),
+            max_entries: Some(2),
+            max_path_depth: Some(3),
+            ..Default::default()
+        };
+        deploy_archive(zip(), &dir.path().join("app"), &[], &within).unwrap();
+    }
+
+    fn sha256_hex(data: &str) -> String {
+        hex::encode(Sha256::digest(data.as_bytes()))
+    }
+
+    #[test]
+    fn checksums_file_is_verified() {
+        let sums = format!("{}  a.txt\n{} *./sub/b.txt\n", sha256_hex("a"), sha256_hex("b"));
+        let extract = Extract { checksums: Some("SHA256SUMS".to_owned()), ..Default::default() };
+        let deploy = |entries: &[(&str, Option<&str>)]| {
+            let dir = tempfile::tempdir().unwrap();
+            deploy_archive(build_zip(entries), &dir.path().join("app"), &[], &extract).map_err(|e| format!("{e:#}"))
+        };
+
+        deploy(&[("SHA256SUMS", Some(&sums)), ("a.txt", Some("a")), ("sub/b.txt", Some("b"))]).unwrap();
+
+        let err = deploy(&[("SHA256SUMS", Some(&sums)), ("a.txt", Some("tampered")), ("sub/b.txt", Some("b"))]).unwrap_err();
+        assert!(err.contains("a.txt has sha256"), "got: {err}");
+
+        let err = deploy(&[("SHA256SUMS", Some(&sums)), ("a.txt", Some("a")), ("sub/b.txt", Some("b")), ("c.txt", Some("c"))])
+            .unwrap_err();
+        assert!(err.contains("c.txt is not listed"), "got: {err}");
+
+        let err = deploy(&[("SHA256SUMS", Some(&sums)), ("a.txt", Some("a"))]).unwrap_err();
+        assert!(err.contains("not in the artifact: sub/b.txt"), "got: {err}");
+
+        let err = deploy(&[("a.txt", Some("a"))]).unwrap_err();
+        assert!(err.contains("checksums file missing"), "got: {err}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let aliased = build_tar(&[("SHA256SUMS", Some(&sums)), ("a.txt", Some("a")), ("sub/b.txt", Some("b"))], &[("alias", "sub")], Format::Tar);
+        let err = deploy_archive(aliased, &dir.path().join("app"), &[], &extract).unwrap_err();
+        assert!(format!("{err:#}").contains("alias is a symlink"), "got: {err:#}");
+    }
+
+    /// A minisign key pair as `(public key line, signer)`; the signer returns the
+    /// `.minisig` file contents for a message (legacy, non-prehashed mode).
+    fn minisign_keys(seed: u8) -> (String, impl Fn(&str) -> String) {
+        use base64::Engine;
+        use ed25519_dalek::Signer;
+
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let key = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]);
+        let key_id = [seed; 8];
+        let public = b64.encode([&b"Ed"[..], &key_id, key.verifying_key().as_bytes()].concat());
+        let sign = move |message: &str| {
+            let signature = key.sign(message.as_bytes()).to_bytes();
+            let trusted = "timestamp:0";
+            let global = key.sign(&[&signature[..], trusted.as_bytes()].concat()).to_bytes();
+            format!(
+                "untrusted comment: test\n{}\ntrusted comment: {trusted}\n{}\n",
+                b64.encode([&b"Ed"[..], &key_id, &signature].concat()),
+                b64.encode(global)
+            )
+        };
+        (public, sign)
+    }
+
+    #[test]
+    fn signed_checksums_are_required_and_verified() {
+        let (public, sign) = minisign_keys(1);
+        let (_, sign_other) = minisign_keys(2);
+        let sums = format!("{}  a.txt\n", sha256_hex("a"));
+        let extract = Extract {
+            checksums: Some("SHA256SUMS".to_owned()),
+            signing_key: Some(public),
+            ..Default::default()
+        };
+        let deploy = |entries: &[(&str, Option<&str>)]| {
+            let dir = tempfile::tempdir().unwrap();
+            deploy_archive(build_zip(entries), &dir.path().join("app"), &[], &extract).map_err(|e| format!("{e:#}"))
+        };
+
+        let signature = sign(&sums);
+        deploy(&[("SHA256SUMS", Some(&sums)), ("SHA256SUMS.minisig", Some(&signature)), ("a.txt", Some("a"))]).unwrap();
+
+        let err = deploy(&[("SHA256SUMS", Some(&sums)), ("a.txt", Some("a"))]).unwrap_err();
+        assert!(err.contains("signature file missing"), "got: {err}");
+
+        let forged = sign_other(&sums);
+        let err = deploy(&[("SHA256SUMS", Some(&sums)), ("SHA256SUMS.minisig", Some(&forged)), ("a.txt", Some("a"))]).unwrap_err();
+        assert!(err.contains("signature verification"), "got: {err}");
+
+        // A manifest changed after signing (to cover a tampered file) fails too.
+        let tampered = format!("{}  a.txt\n", sha256_hex("evil"));
+        let err = deploy(&[("SHA256SUMS", Some(&tampered)), ("SHA256SUMS.minisig", Some(&signature)), ("a.txt", Some("evil"))])
+            .unwrap_err();
+        assert!(err.contains("bad signature"), "got: {err}");
+    }
+
+    #[tokio::test]
+    async fn download_must_match_api_digest() {
+        use warp::Filter;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/artifact", listener.local_addr().unwrap());
+        tokio::spawn(warp::serve(warp::path("artifact").map(|| "archive bytes")).incoming(listener).run());
+
+        let digest = format!("sha256:{}", sha256_hex("archive bytes"));
+        let mut file = fetch_to_temp_file(&url, "token", None, Some(&digest), &config::Retry::default()).await.unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "archive bytes");
+
+        let digest = format!("sha256:{}", sha256_hex("other bytes"));
+        let err = fetch_to_temp_file(&url, "token", None, Some(&digest), &config::Retry::default()).await.unwrap_err();
+        assert!(err.to_string().contains("digest mismatch"), "got: {err:#}");
+    }
+
+    fn fast_retry() -> config::Retry {
+        config::Retry { max_attempts: 3, base_delay_ms: 1, max_delay_secs: 5 }
+    }
+
+    /// Serves `GET /` answering the n-th request with `replies[n]` (status, headers),
+    /// and `ok` once they run out. Returns the URL and the request counter.
+    async fn flaky_server(replies: Vec<(u16, Vec<(&'static str, String)>)>) -> (String, Arc<AtomicUsize>) {
+        use warp::Filter;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+        let route = warp::any().map(move || {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            let mut response = warp::http::Response::builder();
+            match replies.get(n) {
+                Some((status, headers)) => {
+                    response = response.status(*status);
+                    for (name, value) in headers {
+                        response = response.header(*name, value);
+                    }
+                    response.body("failure".to_owned()).unwrap()
+                }
+                None => response.body("ok".to_owned()).unwrap(),
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(warp::serve(route).incoming(listener).run());
+        (url, count)
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let (url, count) = flaky_server(vec![(503, vec![]), (429, vec![("retry-after", "0".to_owned())])]).await;
+        let response = send_with_retry(&fast_retry(), || CLIENT.get(&url)).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let (url, count) = flaky_server(vec![(502, vec![]), (502, vec![]), (502, vec![])]).await;
+        let err = send_with_retry(&fast_retry(), || CLIENT.get(&url)).await.unwrap_err();
+        assert!(format!("{err:#}").contains("giving up after 3 attempt(s)"), "got: {err:#}");
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_and_long_rate_limits_are_not_retried() {
+        let (url, count) = flaky_server(vec![(404, vec![])]).await;
+        assert!(send_with_retry(&fast_retry(), || CLIENT.get(&url)).await.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        let reset = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3600;
+        let limited = vec![("x-ratelimit-remaining", "0".to_owned()), ("x-ratelimit-reset", reset.to_string())];
+        let (url, count) = flaky_server(vec![(403, limited)]).await;
+        let err = send_with_retry(&fast_retry(), || CLIENT.get(&url)).await.unwrap_err();
+        assert!(format!("{err:#}").contains("rate limited for another"), "got: {err:#}");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_download_resumes_with_range() {
+        let (url, server) = scripted_server(vec![
+            // Promises 10 bytes, sends 5, hangs up.
+            b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello",
+            b"HTTP/1.1 206 Partial Content\r\ncontent-length: 5\r\ncontent-range: bytes 5-9/10\r\n\r\nworld",
+        ])
+        .await;
+
+        let mut file = fetch_to_temp_file(&url, "token", None, None, &fast_retry()).await.unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "helloworld");
+
+        let requests = server.await.unwrap();
+        assert!(!requests[0].contains("range:"), "{requests:?}");
+        assert!(requests[1].contains("range: bytes=5-"), "{requests:?}");
+    }
+
+    /// Serves `replies` to one connection each, in order, returning the requests made.
+    async fn scripted_server(replies: Vec<&'static [u8]>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/artifact", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for reply in replies {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    socket.read_exact(&mut byte).await.unwrap();
+                    request.push(byte[0]);
+                }
+                requests.push(String::from_utf8(request).unwrap().to_ascii_lowercase());
+                socket.write_all(reply).await.unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn resume_at_the_wrong_offset_starts_over() {
+        let (url, server) = scripted_server(vec![
+            b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello",
+            b"HTTP/1.1 206 Partial Content\r\ncontent-length: 7\r\ncontent-range: bytes 3-9/10\r\n\r\nloworld",
+            b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhelloworld",
+        ])
+        .await;
+
+        let mut file = fetch_to_temp_file(&url, "token", None, None, &fast_retry()).await.unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "helloworld");
+
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("range: bytes=5-"), "{requests:?}");
+        assert!(!requests[2].contains("range:"), "{requests:?}");
+    }
+
+    #[tokio::test]
+    async fn resumes_and_retries_share_one_attempt_budget() {
+        let truncated: &[u8] = b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello";
+        let unavailable: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n";
+        let (url, server) = scripted_server(vec![unavailable, truncated, unavailable]).await;
+
+        let err = fetch_to_temp_file(&url, "token", None, None, &fast_retry()).await.unwrap_err();
+        assert!(format!("{err:#}").contains("giving up after 3 attempt(s)"), "got: {err:#}");
+        assert!(!format!("{err:#}").contains("/artifact"), "URL leaked: {err:#}");
+        assert_eq!(server.await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn listing_follows_next_links_until_everything_wanted_is_found() {
+        use warp::Filter;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/artifacts", listener.local_addr().unwrap());
+        let pages = Arc::new(AtomicUsize::new(0));
+        let served = Arc::clone(&pages);
+        let link_base = base.clone();
+        let route = warp::query::<HashMap<String, String>>().map(move |query: HashMap<String, String>| {
+            served.fetch_add(1, Ordering::SeqCst);
+            let page: usize = query.get("page").map_or(1, |p| p.parse().unwrap());
+            let body = serde_json::json!({
+                "total_count": 3,
+                "artifacts": [{ "name": format!("a{page}"), "archive_download_url": "unused" }],
+            });
+            let mut reply = warp::http::Response::builder();
+            if page < 3 {
+                let next = format!("<{link_base}?per_page=100&page={}>; rel=\"next\", <{link_base}?page=3>; rel=\"last\"", page + 1);
+                reply = reply.header("link", next);
+            }
+            reply.body(body.to_string()).unwrap()
+        });
+        tokio::spawn(warp::serve(route).incoming(listener).run());
+
+        let listing = deploy::Listing::Artifacts(base.clone());
+        let (entries, total) = list_entries("token", &fast_retry(), &listing, &base, &[pattern::NamePattern::parse("a2").unwrap()]).await.unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a1", "a2"], "stops once everything wanted is found");
+        assert_eq!(total, Some(3));
+        assert_eq!(pages.load(Ordering::SeqCst), 2);
+
+        let (entries, _) = list_entries("token", &fast_retry(), &listing, &base, &[pattern::NamePattern::parse("missing").unwrap()]).await.unwrap();
+        assert_eq!(entries.len(), 3, "reads every page when something is missing");
+    }
+
+    #[tokio::test]
+    async fn pattern_deploys_every_match_to_its_rendered_target() {
+        use warp::Filter;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let list_base = base.clone();
+        let list = warp::path("artifacts").map(move || {
+            let entries: Vec<_> = ["site-en", "site-ko", "docs"]
+                .iter()
+                .map(|name| serde_json::json!({ "name": name, "archive_download_url": format!("{list_base}/download/{name}") }))
+                .collect();
+            warp::reply::json(&serde_json::json!({ "total_count": 3, "artifacts": entries }))
+        });
+        let download = warp::path!("download" / String).map(|name: String| {
+            let mut bytes = Vec::new();
+            build_zip(&[("index.html", Some(&name))]).read_to_end(&mut bytes).unwrap();
+            bytes
+        });
+        tokio::spawn(warp::serve(list.or(download)).incoming(listener).run());
+
+        let dir = tempfile::tempdir().unwrap();
+        let artifacts = [config::Artifact {
+            name: "site-*".to_owned(),
+            target: format!("{}/{{branch}}/{{capture}}", dir.path().display()),
+            ..Default::default()
+        }];
+        let run = deploy::Run {
+            id: 1,
+            head_branch: Some("feature/x".to_owned()),
+            listing: deploy::Listing::Artifacts(format!("{base}/artifacts")),
+            ..Default::default()
+        };
+
+        let planned = plan("token", "test/repo", &run, &artifacts, &fast_retry()).await.unwrap();
+        let targets: Vec<&str> = planned.iter().map(|p| p.target.as_str()).collect();
+        let root = format!("{}/{}", dir.path().display(), pattern::branch_component("feature/x"));
+        assert_eq!(targets, [format!("{root}/en"), format!("{root}/ko")]);
+        download_artifacts("token", "test/repo", &run, planned, &[], &fast_retry()).await.unwrap();
+
+        let branch = dir.path().join(pattern::branch_component("feature/x"));
+        assert_eq!(read_file(&branch.join("en/index.html")), "site-en");
+        assert_eq!(read_file(&branch.join("ko/index.html")), "site-ko");
+        assert_eq!(dir_entry_names(&branch), ["en", "ko"]);
+    }
+
     #[test]
     fn deploy_replaces_target_instead_of_merging() {
         let dir = tempfile::tempdir().unwrap();
@@ -295,7 +1994,7 @@ mod tests {
         fs::write(target.join("common.txt"), "old contents").unwrap();
 
         let zip = build_zip(&[("common.txt", Some("new contents"))]);
-        deploy_zip(zip, &target, &[]).unwrap();
+        deploy_archive(zip, &target, &[], &Extract::default()).unwrap();
 
         assert!(
             !target.join("stale.txt").exists(),
@@ -306,19 +2005,23 @@ mod tests {
     }
 
     #[test]
-    fn successful_deploy_leaves_no_staging_or_old_debris() {
+    fn successful_deploy_keeps_only_the_previous_version() {
         let dir = tempfile::tempdir().unwrap();
         let target = dir.path().join("app");
         fs::create_dir_all(&target).unwrap();
         fs::write(target.join("v1.txt"), "v1").unwrap();
 
-        let zip = build_zip(&[("v2.txt", Some("v2"))]);
-        deploy_zip(zip, &target, &[]).unwrap();
-
-        // Both the `.app.new-*` staging dir and the `.app.old-*` renamed previous
-        // version must be gone; the parent holds only the live target.
-        assert_eq!(dir_entry_names(dir.path()), ["app"]);
-        assert_eq!(read_file(&target.join("v2.txt")), "v2");
+        deploy_archive(build_zip(&[("v2.txt", Some("v2"))]), &target, &[], &Extract::default()).unwrap();
+        deploy_archive(build_zip(&[("v3.txt", Some("v3"))]), &target, &[], &Extract::default()).unwrap();
+
+        // The `.app.new-*` staging dir is gone; of the renamed previous versions only
+        // the newest (v2) is kept for rollback.
+        let names = dir_entry_names(dir.path());
+        assert_eq!(names.len(), 2, "expected the target and one previous version, got {names:?}");
+        assert_eq!(names[1], "app");
+        assert!(names[0].starts_with(".app.old-"), "got {names:?}");
+        assert_eq!(dir_entry_names(&dir.path().join(&names[0])), ["v2.txt"]);
+        assert_eq!(read_file(&target.join("v3.txt")), "v3");
     }
 
     #[test]
@@ -331,7 +2034,7 @@ mod tests {
             ("safe.txt", Some("safe contents")),
         ]);
 
-        deploy_zip(zip, &target, &[]).unwrap();
+        deploy_archive(zip, &target, &[], &Extract::default()).unwrap();
 
         // The invariant is containment: nothing may land outside the staging dir.
         // zip >= 8 `enclosed_name` skips `..`-underflow entries entirely, but
@@ -361,7 +2064,7 @@ mod tests {
         garbage.write_all(b"this is not a zip archive").unwrap();
         garbage.rewind().unwrap();
 
-        let result = deploy_zip(garbage, &target, &[]);
+        let result = deploy_archive(garbage, &target, &[], &Extract::default());
 
         assert!(result.is_err(), "corrupt archive must fail the deploy");
         assert_eq!(read_file(&target.join("keep.txt")), "precious");
@@ -382,7 +2085,7 @@ mod tests {
         fs::write(target.join("stale.txt"), "not in the new artifact").unwrap();
 
         let zip = build_zip(&[("index.html", Some("<html>v2</html>"))]);
-        deploy_zip(zip, &target, &["var".to_string()]).unwrap();
+        deploy_archive(zip, &target, &["var".to_string()], &Extract::default()).unwrap();
 
         assert_eq!(read_file(&target.join("var").join("db.sqlite")), "precious rows");
         assert!(
@@ -392,7 +2095,9 @@ mod tests {
         assert_eq!(read_file(&target.join("index.html")), "<html>v2</html>");
         assert_eq!(dir_entry_names(&target), ["index.html", "var"]);
         assert_eq!(dir_entry_names(&target.join("var")), ["db.sqlite"]);
-        assert_eq!(dir_entry_names(dir.path()), ["app"], "no .old-*/.new-* debris in the parent");
+        let names = dir_entry_names(dir.path());
+        assert_eq!(names.len(), 2, "only the target and its previous version, got {names:?}");
+        assert!(names[0].starts_with(".app.old-"), "no .new-* debris in the parent, got {names:?}");
     }
 
     #[test]
@@ -405,7 +2110,7 @@ mod tests {
         // The artifact ships its own copy of the preserved dir; the live one must
         // replace it wholesale, not merge with it.
         let zip = build_zip(&[("var/seed.txt", Some("factory seed"))]);
-        deploy_zip(zip, &target, &["var".to_string()]).unwrap();
+        deploy_archive(zip, &target, &["var".to_string()], &Extract::default()).unwrap();
 
         assert_eq!(
             dir_entry_names(&target.join("var")),
@@ -426,7 +2131,7 @@ mod tests {
         // The zip ships no var/ at all, so carrying var/data.db must create the
         // parent directory inside the new target.
         let zip = build_zip(&[("index.html", Some("<html>v2</html>"))]);
-        deploy_zip(zip, &target, &["var/data.db".to_string()]).unwrap();
+        deploy_archive(zip, &target, &["var/data.db".to_string()], &Extract::default()).unwrap();
 
         assert_eq!(read_file(&target.join("var").join("data.db")), "precious rows");
         assert!(
@@ -447,7 +2152,7 @@ mod tests {
         // Nothing to carry: the live target never grew a var/. The copy shipped
         // in the artifact stays as the initial state.
         let zip = build_zip(&[("var/seed.txt", Some("factory seed"))]);
-        deploy_zip(zip, &target, &["var".to_string()]).unwrap();
+        deploy_archive(zip, &target, &["var".to_string()], &Extract::default()).unwrap();
 
         assert_eq!(read_file(&target.join("var").join("seed.txt")), "factory seed");
         assert_eq!(dir_entry_names(&target.join("var")), ["seed.txt"]);
@@ -462,10 +2167,162 @@ mod tests {
         // No previous version: preserve has nothing to carry and must not
         // interfere with the shipped seed.
         let zip = build_zip(&[("var/seed.txt", Some("factory seed"))]);
-        deploy_zip(zip, &target, &["var".to_string()]).unwrap();
+        deploy_archive(zip, &target, &["var".to_string()], &Extract::default()).unwrap();
 
         assert_eq!(read_file(&target.join("var").join("seed.txt")), "factory seed");
         assert_eq!(dir_entry_names(&target.join("var")), ["seed.txt"]);
         assert_eq!(dir_entry_names(dir.path()), ["app"]);
     }
+
+    fn releases_dir(dir: &Path) -> PathBuf {
+        dir.join("app.releases")
+    }
+
+    #[test]
+    fn release_deploy_flips_symlink_and_keeps_previous() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+
+        deploy_release(build_zip(&[("v.txt", Some("v1"))]), &target, "1", &[], 3, &Extract::default()).unwrap();
+        deploy_release(build_zip(&[("v.txt", Some("v2"))]), &target, "2", &[], 3, &Extract::default()).unwrap();
+
+        assert!(fs::symlink_metadata(&target).unwrap().is_symlink());
+        assert_eq!(fs::read_link(&target).unwrap(), Path::new("app.releases/2"));
+        assert_eq!(read_file(&target.join("v.txt")), "v2");
+        assert_eq!(read_file(&releases_dir(dir.path()).join("1").join("v.txt")), "v1");
+        assert_eq!(dir_entry_names(dir.path()), ["app", "app.releases"], "no link or staging debris");
+        assert_eq!(dir_entry_names(&releases_dir(dir.path())), ["1", "2"]);
+    }
+
+    #[test]
+    fn release_deploy_prunes_to_keep_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+
+        for release in ["1", "2", "3", "4"] {
+            deploy_release(build_zip(&[("v.txt", Some(release))]), &target, release, &[], 2, &Extract::default()).unwrap();
+        }
+
+        assert_eq!(dir_entry_names(&releases_dir(dir.path())), ["3", "4"]);
+        assert_eq!(read_file(&target.join("v.txt")), "4");
+    }
+
+    #[test]
+    fn release_redeploy_of_same_run_does_not_clobber_live_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+
+        deploy_release(build_zip(&[("v.txt", Some("first"))]), &target, "7", &[], 3, &Extract::default()).unwrap();
+        deploy_release(build_zip(&[("v.txt", Some("second"))]), &target, "7", &[], 3, &Extract::default()).unwrap();
+
+        assert_eq!(read_file(&target.join("v.txt")), "second");
+        assert_eq!(read_file(&releases_dir(dir.path()).join("7").join("v.txt")), "first");
+        assert_eq!(dir_entry_names(&releases_dir(dir.path())).len(), 2);
+    }
+
+    #[test]
+    fn release_deploy_migrates_plain_directory_and_carries_preserved() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        fs::create_dir_all(target.join("var")).unwrap();
+        fs::write(target.join("var").join("db.sqlite"), "precious rows").unwrap();
+        fs::write(target.join("old.txt"), "plain layout").unwrap();
+
+        let zip = build_zip(&[("index.html", Some("<html>v2</html>"))]);
+        deploy_release(zip, &target, "2", &["var".to_string()], 3, &Extract::default()).unwrap();
+
+        assert!(fs::symlink_metadata(&target).unwrap().is_symlink());
+        assert_eq!(read_file(&target.join("var").join("db.sqlite")), "precious rows");
+        assert_eq!(dir_entry_names(&target), ["index.html", "var"]);
+
+        let releases = dir_entry_names(&releases_dir(dir.path()));
+        assert_eq!(releases.len(), 2);
+        let migrated = releases.iter().find(|name| name.starts_with("migrated-")).expect("plain directory kept as a release");
+        assert_eq!(read_file(&releases_dir(dir.path()).join(migrated).join("old.txt")), "plain layout");
+    }
+
+    #[test]
+    fn corrupt_zip_leaves_live_release_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        deploy_release(build_zip(&[("v.txt", Some("v1"))]), &target, "1", &[], 3, &Extract::default()).unwrap();
+
+        let mut garbage = tempfile::tempfile().unwrap();
+        garbage.write_all(b"this is not a zip archive").unwrap();
+        garbage.rewind().unwrap();
+
+        assert!(deploy_release(garbage, &target, "2", &[], 3, &Extract::default()).is_err());
+        assert_eq!(read_file(&target.join("v.txt")), "v1");
+        assert_eq!(dir_entry_names(&releases_dir(dir.path())), ["1"], "failed release must not linger");
+    }
+
+    fn artifact_at(target: &Path, preserve: &[&str], keep_releases: Option<usize>) -> config::Artifact {
+        config::Artifact {
+            target: target.to_string_lossy().into_owned(),
+            preserve: preserve.iter().map(|p| p.to_string()).collect(),
+            keep_releases,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rollback_restores_previous_version_with_live_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        let preserve = ["var".to_string()];
+        deploy_archive(build_zip(&[("v.txt", Some("v1"))]), &target, &preserve, &Extract::default()).unwrap();
+        deploy_archive(build_zip(&[("v.txt", Some("v2"))]), &target, &preserve, &Extract::default()).unwrap();
+        fs::create_dir_all(target.join("var")).unwrap();
+        fs::write(target.join("var").join("db.sqlite"), "written under v2").unwrap();
+
+        rollback(&artifact_at(&target, &["var"], None), None).unwrap();
+
+        assert_eq!(read_file(&target.join("v.txt")), "v1");
+        assert_eq!(read_file(&target.join("var").join("db.sqlite")), "written under v2");
+
+        // Rolling back again returns to v2: the version replaced by a rollback is
+        // itself kept as the previous one.
+        rollback(&artifact_at(&target, &["var"], None), None).unwrap();
+        assert_eq!(read_file(&target.join("v.txt")), "v2");
+        assert_eq!(read_file(&target.join("var").join("db.sqlite")), "written under v2");
+    }
+
+    #[test]
+    fn rollback_without_previous_version_fails_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        deploy_archive(build_zip(&[("v.txt", Some("v1"))]), &target, &[], &Extract::default()).unwrap();
+
+        assert!(rollback(&artifact_at(&target, &[], None), None).is_err());
+        assert!(
+            rollback(&artifact_at(&target, &[], None), Some("1")).is_err(),
+            "plain layout has no named versions"
+        );
+        assert_eq!(read_file(&target.join("v.txt")), "v1");
+    }
+
+    #[test]
+    fn rollback_release_to_previous_and_named() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        for release in ["1", "2", "3"] {
+            deploy_release(build_zip(&[("v.txt", Some(release))]), &target, release, &["var".to_string()], 5, &Extract::default()).unwrap();
+        }
+        fs::create_dir_all(target.join("var")).unwrap();
+        fs::write(target.join("var").join("db.sqlite"), "live rows").unwrap();
+        let artifact = artifact_at(&target, &["var"], Some(5));
+
+        assert_eq!(rollback(&artifact, None).unwrap(), "2");
+        assert_eq!(read_file(&target.join("v.txt")), "2");
+        assert_eq!(read_file(&target.join("var").join("db.sqlite")), "live rows");
+
+        assert_eq!(rollback(&artifact, Some("1")).unwrap(), "1");
+        assert_eq!(read_file(&target.join("v.txt")), "1");
+        assert_eq!(read_file(&target.join("var").join("db.sqlite")), "live rows");
+
+        assert!(rollback(&artifact, Some("1")).is_err(), "already live");
+        assert!(rollback(&artifact, Some("9")).is_err(), "no such release");
+        assert!(rollback(&artifact, Some("../app")).is_err(), "not a release name");
+        assert_eq!(read_file(&target.join("v.txt")), "1");
+    }
 }
@@ -0,0 +1,407 @@
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{self, GithubReport};
+use crate::deploy;
+use crate::download::CLIENT;
+
+const API_URL: &str = "https://api.github.com";
+
+/// GitHub caps deployment and commit status descriptions at 140 characters.
+const MAX_DESCRIPTION_CHARS: usize = 140;
+
+/// REST API URL of `repository` (`owner/repo`), as webhook payloads carry it in
+/// `repository.url`.
+pub fn repository_url(repository: &str) -> String {
+    format!("{API_URL}/repos/{repository}")
+}
+
+/// The relevant subset of a workflow run, as found both in `workflow_run` webhook
+/// payloads and in the REST API.
+#[derive(Deserialize)]
+pub struct WorkflowRun {
+    pub id: u64,
+    pub head_sha: Option<String>,
+    pub conclusion: Option<String>,
+    pub head_branch: Option<String>,
+    pub name: Option<String>,
+    /// The workflow file, e.g. `.github/workflows/deploy.yml`.
+    pub path: Option<String>,
+    /// 1, then 2, ... for each re-run of the same run id.
+    pub run_attempt: Option<u64>,
+    /// When this attempt started (RFC 3339).
+    pub run_started_at: Option<String>,
+    pub head_commit: Option<HeadCommit>,
+    pub artifacts_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HeadCommit {
+    pub timestamp: Option<String>,
+}
+
+impl WorkflowRun {
+    pub fn position(&self) -> deploy::Position {
+        deploy::Position {
+            commit_time: self.head_commit.as_ref().and_then(|c| c.timestamp.as_deref()).and_then(parse_timestamp),
+            started_at: self.run_started_at.as_deref().and_then(parse_timestamp),
+        }
+    }
+}
+
+/// Unix seconds of an RFC 3339 timestamp as GitHub writes them
+/// (`2024-05-01T12:34:56Z`, `2024-05-01T21:34:56+09:00`), fractions ignored.
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(at) => time.split_at(at),
+        None => return None,
+    };
+    let clock = clock.split('.').next()?;
+    let mut clock = clock.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (clock.next()?.ok()?, clock.next()?.ok()?, clock.next()?.ok()?);
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+        }
+    };
+
+    // Days since the epoch of a proleptic Gregorian date (Howard Hinnant's
+    // `days_from_civil`).
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// The relevant subset of a release, as found both in `release` webhook payloads and
+/// in the REST API.
+#[derive(Deserialize)]
+pub struct Release {
+    pub id: u64,
+    pub tag_name: String,
+    pub assets_url: Option<String>,
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub prerelease: bool,
+}
+
+#[derive(Deserialize)]
+struct WorkflowRunList {
+    #[serde(default)]
+    workflow_runs: Vec<WorkflowRun>,
+}
+
+/// The most recent successful run of `deploy_conf.repository` passing the entry's
+/// `branch` and `workflow` gates, for redeploying without a webhook.
+pub async fn latest_successful_run(token: &str, deploy_conf: &config::Deploy) -> anyhow::Result<WorkflowRun> {
+    let mut url = reqwest::Url::parse_with_params(
+        &format!("{}/actions/runs", repository_url(&deploy_conf.repository)),
+        [("status", "success"), ("per_page", "100")],
+    )?;
+    if let Some(branch) = deploy_conf.branch.as_ref().and_then(config::Patterns::exact) {
+        url.query_pairs_mut().append_pair("branch", branch);
+    }
+
+    let list: WorkflowRunList = CLIENT
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("failed to list workflow runs")?;
+
+    // Runs come newest first. Workflow gates and branch patterns are beyond what the
+    // API can filter on.
+    list.workflow_runs
+        .into_iter()
+        .filter(|run| run.conclusion.as_deref() == Some("success"))
+        .filter(|run| deploy_conf.branch_matches(run.head_branch.as_deref()))
+        .filter(|run| deploy_conf.workflow_matches(run.name.as_deref(), run.path.as_deref()))
+        .find(|run| run.artifacts_url.as_deref().is_some_and(|u| !u.is_empty()))
+        .with_context(|| format!("no recent successful run of {} matches the entry", deploy_conf.repository))
+}
+
+/// The latest published release of `deploy_conf.repository` passing the entry's `tag`
+/// gate, for redeploying a release-sourced entry without a webhook. Drafts and
+/// prereleases never count.
+pub async fn latest_release(token: &str, deploy_conf: &config::Deploy) -> anyhow::Result<Release> {
+    let releases_url = format!("{}/releases", repository_url(&deploy_conf.repository));
+    if deploy_conf.tag.is_none() {
+        // GitHub already skips drafts and prereleases here.
+        return CLIENT
+            .get(format!("{releases_url}/latest"))
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("failed to fetch the latest release of {}", deploy_conf.repository));
+    }
+
+    let releases: Vec<Release> = CLIENT
+        .get(format!("{releases_url}?per_page=100"))
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .with_context(|| format!("failed to list the releases of {}", deploy_conf.repository))?;
+
+    // Newest first.
+    releases
+        .into_iter()
+        .filter(|release| !release.draft && !release.prerelease)
+        .find(|release| deploy_conf.tag_matches(&release.tag_name))
+        .with_context(|| format!("no recent release of {} matches the entry's tag", deploy_conf.repository))
+}
+
+/// A deploy being reported to GitHub, per the entry's `github_report` setting.
+pub enum Report {
+    Deployment { statuses_url: String, environment: String },
+    CommitStatus { statuses_url: String, context: String },
+}
+
+#[derive(Deserialize)]
+struct Deployment {
+    statuses_url: String,
+}
+
+impl Report {
+    /// Creates the Deployment (or pending commit status) for `run`'s head commit, or
+    /// for a release's tag (Deployments only: commit statuses need a SHA).
+    /// Best effort, like notifications: a failure is logged and the deploy goes on
+    /// unreported.
+    pub async fn start(token: &str, deploy_conf: &config::Deploy, run: &deploy::Run) -> Option<Self> {
+        let kind = deploy_conf.github_report?;
+        let git_ref = match (kind, &run.head_sha, &run.tag) {
+            (_, Some(sha), _) => sha.as_str(),
+            (GithubReport::Deployment, None, Some(tag)) => tag.as_str(),
+            _ => {
+                eprintln!("! Warning: run {} of {} has no head SHA to report on.", run.id, deploy_conf.repository);
+                return None;
+            }
+        };
+        let preview = deploy_conf.deploys_previews();
+        let environment = match (&deploy_conf.environment, preview) {
+            (Some(environment), false) => environment.clone(),
+            (None, false) => "production".to_owned(),
+            (environment, true) => format!(
+                "{}/{}",
+                environment.as_deref().unwrap_or("preview"),
+                run.head_branch.as_deref().unwrap_or_default()
+            ),
+        };
+
+        let report = match kind {
+            GithubReport::Deployment => {
+                let created: anyhow::Result<Deployment> = async {
+                    Ok(CLIENT
+                        .post(format!("{}/deployments", run.repository_url))
+                        .bearer_auth(token)
+                        .json(&json!({
+                            "ref": git_ref,
+                            "environment": environment,
+                            "description": format!("lanchanto: run {}", run.id),
+                            // Deploy exactly this commit: no merging the default branch
+                            // in, and the run that built it already passed.
+                            "auto_merge": false,
+                            "required_contexts": [],
+                            "transient_environment": preview,
+                            "production_environment": !preview,
+                        }))
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?)
+                }
+                .await;
+                match created {
+                    Ok(deployment) => Self::Deployment { statuses_url: deployment.statuses_url, environment },
+                    Err(e) => {
+                        eprintln!("! Warning: failed to create GitHub deployment for {}: {:#}", deploy_conf.repository, e);
+                        return None;
+                    }
+                }
+            }
+            GithubReport::CommitStatus => Self::CommitStatus {
+                statuses_url: format!("{}/statuses/{git_ref}", run.repository_url),
+                context: format!("lanchanto/{environment}"),
+            },
+        };
+
+        report.post(token, &deploy_conf.repository, None, "Deploying").await;
+        Some(report)
+    }
+
+    /// Reports the outcome: success, or failure with the error as description.
+    pub async fn finish(&self, token: &str, repository: &str, error: Option<&str>) {
+        match error {
+            None => self.post(token, repository, Some(true), "Deployed").await,
+            Some(error) => self.post(token, repository, Some(false), error).await,
+        }
+    }
+
+    /// `succeeded` is `None` while the deploy is in progress.
+    async fn post(&self, token: &str, repository: &str, succeeded: Option<bool>, description: &str) {
+        let description: String = description.chars().take(MAX_DESCRIPTION_CHARS).collect();
+        let (url, body) = match self {
+            Self::Deployment { statuses_url, environment } => {
+                let state = match succeeded {
+                    None => "in_progress",
+                    Some(true) => "success",
+                    Some(false) => "failure",
+                };
+                (statuses_url, json!({ "state": state, "environment": environment, "description": description }))
+            }
+            Self::CommitStatus { statuses_url, context } => {
+                let state = match succeeded {
+                    None => "pending",
+                    Some(true) => "success",
+                    Some(false) => "failure",
+                };
+                (statuses_url, json!({ "state": state, "context": context, "description": description }))
+            }
+        };
+
+        let sent = CLIENT
+            .post(url)
+            .bearer_auth(token)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = sent {
+            eprintln!("! Warning: failed to report deploy status of {} to GitHub: {}", repository, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    /// A fake GitHub API recording `(path, body)` of every POST; creating a deployment
+    /// answers with a `statuses_url` on the same server.
+    async fn fake_api() -> (SocketAddr, mpsc::UnboundedReceiver<(String, serde_json::Value)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let route = warp::post()
+            .and(warp::path::full())
+            .and(warp::body::json())
+            .map(move |path: warp::path::FullPath, body: serde_json::Value| {
+                let path = path.as_str().to_owned();
+                tx.send((path.clone(), body)).unwrap();
+                warp::reply::json(&json!({ "statuses_url": format!("http://{addr}{path}/7/statuses") }))
+            });
+        tokio::spawn(warp::serve(route).incoming(listener).run());
+        (addr, rx)
+    }
+
+    #[test]
+    fn timestamps_parse_with_offsets() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56Z"), Some(1_714_566_896));
+        assert_eq!(parse_timestamp("2024-05-01T21:34:56.123+09:00"), Some(1_714_566_896));
+        assert_eq!(parse_timestamp("2024-05-01T07:34:56-05:00"), Some(1_714_566_896));
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    fn run_at(addr: SocketAddr) -> deploy::Run {
+        deploy::Run {
+            id: 42,
+            head_sha: Some("abc123".to_owned()),
+            repository_url: format!("http://{addr}/repos/test/repo"),
+            ..Default::default()
+        }
+    }
+
+    fn entry(report: GithubReport, environment: Option<&str>) -> config::Deploy {
+        config::Deploy {
+            repository: "test/repo".to_owned(),
+            github_report: Some(report),
+            environment: environment.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn deployment_is_created_then_marked_in_progress_and_failed() {
+        let (addr, mut rx) = fake_api().await;
+        let deploy_conf = entry(GithubReport::Deployment, Some("staging"));
+
+        let report = Report::start("token", &deploy_conf, &run_at(addr)).await.expect("deployment created");
+        report.finish("token", "test/repo", Some("failed to deploy artifact bundle")).await;
+
+        let (path, body) = rx.recv().await.unwrap();
+        assert_eq!(path, "/repos/test/repo/deployments");
+        assert_eq!(body["ref"], "abc123");
+        assert_eq!(body["environment"], "staging");
+
+        let (path, body) = rx.recv().await.unwrap();
+        assert_eq!(path, "/repos/test/repo/deployments/7/statuses");
+        assert_eq!(body["state"], "in_progress");
+
+        let (_, body) = rx.recv().await.unwrap();
+        assert_eq!(body["state"], "failure");
+        assert_eq!(body["description"], "failed to deploy artifact bundle");
+    }
+
+    #[tokio::test]
+    async fn previews_report_to_their_own_environment() {
+        let (addr, mut rx) = fake_api().await;
+        let deploy_conf = config::Deploy { previews: true, ..entry(GithubReport::Deployment, None) };
+        let run = deploy::Run { head_branch: Some("feature/x".to_owned()), ..run_at(addr) };
+
+        Report::start("token", &deploy_conf, &run).await.expect("deployment created");
+        let (_, body) = rx.recv().await.unwrap();
+        assert_eq!(body["environment"], "preview/feature/x");
+        assert_eq!(body["transient_environment"], true);
+        assert_eq!(body["production_environment"], false);
+    }
+
+    #[tokio::test]
+    async fn commit_status_is_pending_then_success() {
+        let (addr, mut rx) = fake_api().await;
+        let deploy_conf = entry(GithubReport::CommitStatus, None);
+
+        let report = Report::start("token", &deploy_conf, &run_at(addr)).await.expect("reporting enabled");
+        report.finish("token", "test/repo", None).await;
+
+        let (path, body) = rx.recv().await.unwrap();
+        assert_eq!(path, "/repos/test/repo/statuses/abc123");
+        assert_eq!(body["state"], "pending");
+        assert_eq!(body["context"], "lanchanto/production");
+
+        let (_, body) = rx.recv().await.unwrap();
+        assert_eq!(body["state"], "success");
+    }
+
+    #[tokio::test]
+    async fn reporting_is_off_by_default() {
+        let deploy_conf = config::Deploy::default();
+        assert!(Report::start("token", &deploy_conf, &deploy::Run::default()).await.is_none());
+    }
+}
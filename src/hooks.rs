@@ -0,0 +1,87 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio::process::Command;
+
+/// A hook that never returns would hold its entry's deploy lock forever; ten minutes
+/// covers slow service restarts and migrations with plenty of margin.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Runs each command with `sh -c` in order, stopping at the first failure. `stage`
+/// (`pre_deploy`, `post_deploy`, ...) only labels the log. Output is captured and
+/// written to the deploy log line by line, so hooks show up next to the deploy
+/// they belong to.
+pub async fn run(stage: &str, commands: &[String], env: &[(&str, String)]) -> anyhow::Result<()> {
+    for command in commands {
+        println!("> Running {} hook: {}", stage, command);
+
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // On timeout the future is dropped; don't leave the hook running behind us.
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to start {stage} hook `{command}`"))?;
+
+        let output = tokio::time::timeout(HOOK_TIMEOUT, child.wait_with_output())
+            .await
+            .with_context(|| format!("{stage} hook `{command}` timed out after {}s", HOOK_TIMEOUT.as_secs()))?
+            .with_context(|| format!("failed to wait for {stage} hook `{command}`"))?;
+
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            println!("  {stage} | {line}");
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            eprintln!("  {stage} ! {line}");
+        }
+
+        if !output.status.success() {
+            bail!("{stage} hook `{command}` failed ({})", output.status);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(list: &[&str]) -> Vec<String> {
+        list.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn hooks_run_in_order_with_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let env = [
+            ("LANCHANTO_RUN_ID", "42".to_string()),
+            ("OUT", out.to_string_lossy().into_owned()),
+        ];
+
+        run("test", &commands(&[r#"echo "first $LANCHANTO_RUN_ID" >> "$OUT""#, r#"echo second >> "$OUT""#]), &env)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "first 42\nsecond\n");
+    }
+
+    #[tokio::test]
+    async fn failing_hook_stops_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let env = [("MARKER", marker.to_string_lossy().into_owned())];
+
+        let err = run("pre_deploy", &commands(&["exit 3", r#"touch "$MARKER""#]), &env)
+            .await
+            .unwrap_err();
+
+        assert!(format!("{err:#}").contains("pre_deploy hook `exit 3` failed"), "got: {err:#}");
+        assert!(!marker.exists(), "commands after a failing hook must not run");
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{config, deploy};
+
+/// Deploys accepted but not finished yet, one JSON file each in `<state_dir>/jobs`,
+/// written before the webhook is answered and removed once the deploy is over (or
+/// superseded). Whatever is left at startup was cut short by a restart or crash and
+/// is deployed again. Without `state_dir`, nothing is journaled.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    dir: Option<PathBuf>,
+    next_id: AtomicU64,
+}
+
+/// One journaled deploy.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Job {
+    /// Index of the entry in `config.deploy`: one repository may have several. Its
+    /// repository and source are kept too, to tell whether the config changed since.
+    pub entry: usize,
+    pub repository: String,
+    pub source: config::Source,
+    pub run: deploy::Run,
+    /// Set once the deploy started: left set at startup, it was interrupted midway
+    /// and may have left staging directories behind.
+    #[serde(default)]
+    pub started: bool,
+}
+
+impl Jobs {
+    /// Opens the journal in `state_dir` (creating it), or journals nothing without one.
+    pub fn open(state_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(state_dir) = state_dir else {
+            return Ok(Self::default());
+        };
+        let dir = state_dir.join("jobs");
+        fs::create_dir_all(&dir)?;
+
+        let mut next_id = 1;
+        for entry in fs::read_dir(&dir)? {
+            if let Some(id) = job_id(&entry?.path()) {
+                next_id = next_id.max(id + 1);
+            }
+        }
+        Ok(Self { dir: Some(dir), next_id: AtomicU64::new(next_id) })
+    }
+
+    /// Journals `run` as accepted for entry number `entry`, `deploy_conf`, returning
+    /// its job id. A journal that can't be written is logged, not fatal: the deploy
+    /// goes ahead, just without surviving a restart.
+    pub fn accept(&self, entry: usize, deploy_conf: &config::Deploy, run: &deploy::Run) -> Option<u64> {
+        let dir = self.dir.as_ref()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = JobRef::new(entry, deploy_conf, run, false);
+        match write_job(&job_path(dir, id), &job) {
+            Ok(()) => Some(id),
+            Err(e) => {
+                eprintln!("! Warning: failed to journal the deploy of run {} of {}: {:#}", run.id, deploy_conf.repository, e);
+                None
+            }
+        }
+    }
+
+    /// Marks job `id`, about to deploy, as started.
+    pub fn start(&self, id: u64, entry: usize, deploy_conf: &config::Deploy, run: &deploy::Run) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let job = JobRef::new(entry, deploy_conf, run, true);
+        if let Err(e) = write_job(&job_path(dir, id), &job) {
+            eprintln!("! Warning: failed to update journaled job {}: {:#}", id, e);
+        }
+    }
+
+    /// Drops job `id` from the journal: deployed, failed or superseded.
+    pub fn finish(&self, id: u64) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if let Err(e) = fs::remove_file(job_path(dir, id)) {
+            eprintln!("! Warning: failed to remove journaled job {}: {}", id, e);
+        }
+    }
+
+    /// The journaled jobs, oldest first, each with its id (set as `run.job`).
+    /// Unreadable files are logged and skipped.
+    pub fn unfinished(&self) -> anyhow::Result<Vec<Job>> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let mut ids: Vec<u64> = fs::read_dir(dir)?.filter_map(|entry| job_id(&entry.ok()?.path())).collect();
+        ids.sort_unstable();
+
+        let mut jobs = Vec::with_capacity(ids.len());
+        for id in ids {
+            let path = job_path(dir, id);
+            let job = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| serde_json::from_slice::<Job>(&data).map_err(anyhow::Error::from));
+            match job {
+                Ok(mut job) => {
+                    job.run.job = Some(id);
+                    jobs.push(job);
+                }
+                Err(e) => eprintln!("! Warning: skipping unreadable journaled job {}: {:#}", path.display(), e),
+            }
+        }
+        Ok(jobs)
+    }
+}
+
+/// `Job`, borrowing its parts for writing.
+#[derive(Serialize)]
+struct JobRef<'a> {
+    entry: usize,
+    repository: &'a str,
+    source: config::Source,
+    run: &'a deploy::Run,
+    started: bool,
+}
+
+impl<'a> JobRef<'a> {
+    fn new(entry: usize, deploy_conf: &'a config::Deploy, run: &'a deploy::Run, started: bool) -> Self {
+        Self { entry, repository: &deploy_conf.repository, source: deploy_conf.source, run, started }
+    }
+}
+
+fn job_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:020}.json"))
+}
+
+fn job_id(path: &Path) -> Option<u64> {
+    path.file_name()?.to_str()?.strip_suffix(".json")?.parse().ok()
+}
+
+/// Writes via a synced rename, so neither a crash nor a power loss leaves a torn or
+/// missing job behind once the webhook is answered.
+fn write_job(path: &Path, job: &JobRef) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).with_context(|| format!("failed to write {}", tmp.display()))?;
+    file.write_all(&serde_json::to_vec(job)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_survive_until_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |id| deploy::Run {
+            id,
+            head_branch: Some("main".to_owned()),
+            listing: deploy::Listing::Artifacts(format!("https://api.github.com/runs/{id}/artifacts")),
+            ..Default::default()
+        };
+
+        let entry = config::Deploy { repository: "a/b".to_owned(), ..Default::default() };
+
+        let jobs = Jobs::open(Some(dir.path())).unwrap();
+        let first = jobs.accept(0, &entry, &run(1)).unwrap();
+        let second = jobs.accept(1, &entry, &run(2)).unwrap();
+        let third = jobs.accept(0, &entry, &run(3)).unwrap();
+        jobs.start(second, 1, &entry, &run(2));
+        jobs.finish(first);
+
+        // Restarted.
+        let jobs = Jobs::open(Some(dir.path())).unwrap();
+        let unfinished = jobs.unfinished().unwrap();
+        let summary: Vec<_> = unfinished.iter().map(|job| (job.entry, job.run.id, job.run.job, job.started)).collect();
+        assert_eq!(summary, [(1, 2, Some(second), true), (0, 3, Some(third), false)]);
+        assert_eq!(unfinished[1].run.head_branch.as_deref(), Some("main"));
+        assert!(matches!(&unfinished[1].run.listing, deploy::Listing::Artifacts(url) if url.ends_with("/3/artifacts")));
+
+        // New ids never reuse a journaled one.
+        assert!(jobs.accept(0, &entry, &run(4)).unwrap() > third);
+    }
+
+    #[test]
+    fn nothing_is_journaled_without_state_dir() {
+        let jobs = Jobs::open(None).unwrap();
+        assert_eq!(jobs.accept(0, &config::Deploy::default(), &deploy::Run::default()), None);
+        assert!(jobs.unfinished().unwrap().is_empty());
+    }
+}
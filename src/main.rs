@@ -1,14 +1,24 @@
 use std::convert::Infallible;
-use std::sync::OnceLock;
+use std::sync::atomic::Ordering;
+use std::sync::{OnceLock, PoisonError};
+use std::time::Duration;
 
+use anyhow::Context;
 use bytes::Bytes;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::Deserialize;
 use warp::{http::{HeaderMap, StatusCode}, reply::WithStatus, Filter};
 
 mod config;
+mod deliveries;
+mod jobs;
 mod signature;
 mod download;
+mod hooks;
+mod deploy;
+mod github;
+mod notify;
+mod pattern;
 
 /// GitHub caps webhook payloads at 25 MiB, but `workflow_run` payloads are a few tens
 /// of KiB; 1 MiB bounds what a client can make us buffer while leaving ample margin.
@@ -21,32 +31,72 @@ struct Args {
 
     #[arg(short, long)]
     config: String,
+
+    /// Without a subcommand, listens for webhooks.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Swaps every artifact target of a repository back to its previous version.
+    /// This bypasses a running server's per-repository lock; prefer its
+    /// `/rollback` endpoint when one is up.
+    Rollback {
+        /// `owner/repo`, as in the config's `repository`.
+        repository: String,
+
+        /// Release to roll back to (release layout only), instead of the previous one.
+        #[arg(long)]
+        to: Option<String>,
+    },
 }
 
 /// Process-lifetime home of the loaded config. Written exactly once in `main`;
 /// everything else receives the config as a plain reference.
 static CONFIG: OnceLock<config::Config> = OnceLock::new();
 
-/// The relevant subset of a `workflow_run` webhook payload.
+/// The relevant subset of a `workflow_run`, `release`, `delete` or `pull_request`
+/// webhook payload.
 #[derive(Deserialize)]
 struct Payload {
     #[serde(default)]
     action: String,
     repository: Repository,
-    workflow_run: Option<WorkflowRun>,
+    workflow_run: Option<github::WorkflowRun>,
+    release: Option<github::Release>,
+    /// `delete`: the deleted branch or tag, and which of the two it was.
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    ref_type: Option<String>,
+    pull_request: Option<PullRequest>,
+    /// The `X-GitHub-Delivery` GUID, the same for every redelivery.
+    #[serde(skip)]
+    delivery: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct Repository {
-    full_name: String,
+struct PullRequest {
+    head: PullRequestHead,
 }
 
 #[derive(Deserialize)]
-struct WorkflowRun {
-    conclusion: Option<String>,
-    head_branch: Option<String>,
-    name: Option<String>,
-    artifacts_url: Option<String>,
+struct PullRequestHead {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    /// `None` once a fork is deleted.
+    repo: Option<Repository>,
+}
+
+#[derive(Deserialize)]
+struct RollbackQuery {
+    to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+    url: Option<String>,
 }
 
 #[tokio::main]
@@ -54,14 +104,25 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     let loaded = config::Config::load(&args.config)?;
-    for deploy in &loaded.deploy {
-        if deploy.branch.is_none() {
+    let config: &'static config::Config = CONFIG.get_or_init(|| loaded);
+
+    if let Some(Command::Rollback { repository, to }) = args.command {
+        let (_, deploy_conf) = config.main_entry(&repository).with_context(|| format!("unknown repository {repository}"))?;
+        deploy::rollback(deploy_conf, to.as_deref()).await?;
+        return Ok(());
+    }
+
+    for deploy in &config.deploy {
+        if deploy.source == config::Source::WorkflowRun && deploy.branch.is_none() {
             eprintln!("! Warning: deploy entry for {} has no `branch` filter; successful runs of ANY branch will deploy.", deploy.repository);
         }
     }
-    let config: &'static config::Config = CONFIG.get_or_init(|| loaded);
 
-    let main_page = warp::get().map(|| "Hello, world!\n");
+    resume_jobs(config)?;
+
+    let status = warp::get()
+        .and(warp::path!("status"))
+        .map(move || warp::reply::json(&status_json(config)));
 
     let github = warp::post()
         .and(warp::path("github"))
@@ -71,27 +132,93 @@ async fn main() -> anyhow::Result<()> {
         .and(warp::body::bytes())
         .and_then(handle_github);
 
+    let redeploy = warp::post()
+        .and(warp::path!("deploy" / String / String))
+        .and(warp::any().map(move || config))
+        .and(warp::header::headers_cloned())
+        .and_then(handle_redeploy);
+
+    let rollback = warp::post()
+        .and(warp::path!("rollback" / String / String))
+        .and(warp::any().map(move || config))
+        .and(warp::header::headers_cloned())
+        .and(warp::query::<RollbackQuery>())
+        .and_then(handle_rollback);
+
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("failed to handle SIGTERM")?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        println!("Shutting down: refusing new deliveries, waiting for running deploys.");
+        config.draining.store(true, Ordering::SeqCst);
+    };
+
     println!("Listening on 0.0.0.0:{}", args.port);
-    warp::serve(main_page.or(github)).run(([0, 0, 0, 0], args.port)).await;
+    warp::serve(status.or(github).or(redeploy).or(rollback))
+        .bind(([0, 0, 0, 0], args.port))
+        .await
+        .graceful(shutdown)
+        .run()
+        .await;
 
+    finish_deploys(config).await;
     Ok(())
 }
 
-async fn handle_github(config: &'static config::Config, headers: HeaderMap, body: Bytes) -> Result<impl warp::Reply, Infallible> {
-    if let Err(e) = signature::verify(config, &headers, &body) {
-        eprintln!("! Error: invalid credential: {}", e);
-        return Ok(reply_error(StatusCode::FORBIDDEN, "invalid credential"));
+/// Waits, up to `shutdown_timeout_secs`, for every entry's running deploy (or
+/// rollback, or preview removal) to finish: killed between the renames of a swap, a
+/// deploy would leave its target missing until the next start cleans up. Queued
+/// deploys don't start anymore; they stay journaled for the next start.
+async fn finish_deploys(config: &'static config::Config) {
+    let all_done = async {
+        for deploy_conf in &config.deploy {
+            drop(deploy_conf.queue.lock().await);
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(config.shutdown_timeout_secs), all_done).await.is_err() {
+        eprintln!(
+            "! Warning: deploys still running after {}s; exiting anyway. Journaled ones resume at the next start.",
+            config.shutdown_timeout_secs
+        );
     }
+}
 
-    // Only `workflow_run` carries deployable artifacts. Everything else (`ping`,
-    // `check_suite`, ...) is acknowledged and ignored so the hook stays green in
-    // GitHub's UI; some of those events also have `action == "completed"`.
-    let event = headers.get("X-GitHub-Event").and_then(|v| v.to_str().ok());
-    if event != Some("workflow_run") {
-        return Ok(reply_ok());
+/// The reply to every delivery and operation request once shutting down.
+fn refuse_if_draining(config: &config::Config) -> Option<WithStatus<warp::reply::Json>> {
+    config
+        .draining
+        .load(Ordering::SeqCst)
+        .then(|| reply_error(StatusCode::SERVICE_UNAVAILABLE, "shutting down"))
+}
+
+async fn handle_github(config: &'static config::Config, headers: HeaderMap, body: Bytes) -> Result<impl warp::Reply, Infallible> {
+    if let Some(reply) = refuse_if_draining(config) {
+        return Ok(reply);
+    }
+    match signature::verify(config, &headers, &body) {
+        Ok(matched) if matched.of > 1 => {
+            println!("> Signature matched webhook secret #{} of {}.", matched.index + 1, matched.of);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("! Error: invalid credential: {}", e);
+            return Ok(reply_error(StatusCode::FORBIDDEN, "invalid credential"));
+        }
     }
 
-    let payload: Payload = match serde_json::from_slice(&body) {
+    // Only `workflow_run` and `release` carry deployable files, and `delete` and
+    // `pull_request` retire branch previews. Everything else (`ping`, `check_suite`,
+    // ...) is acknowledged and ignored so the hook stays green in GitHub's UI; some of
+    // those events also have `action == "completed"`.
+    let event = match headers.get("X-GitHub-Event").and_then(|v| v.to_str().ok()) {
+        Some(event @ ("workflow_run" | "release" | "delete" | "pull_request")) => event,
+        _ => return Ok(reply_ok()),
+    };
+
+    let mut payload: Payload = match serde_json::from_slice(&body) {
         Ok(v) => v,
         Err(e) => {
             eprintln!("Error parsing webhook body: {}", e);
@@ -99,60 +226,410 @@ async fn handle_github(config: &'static config::Config, headers: HeaderMap, body
         }
     };
 
-    let repo_full = payload.repository.full_name;
-    println!("Hook received: {} workflow_run {}", repo_full, payload.action);
+    payload.delivery = headers.get("X-GitHub-Delivery").and_then(|v| v.to_str().ok()).map(str::to_owned);
 
-    if payload.action != "completed" {
+    let repo_full = payload.repository.full_name.clone();
+    println!("Hook received: {} {} {}", repo_full, event, payload.action);
+
+    let wanted = match event {
+        "release" => "published",
+        "pull_request" => "closed",
+        // `delete` payloads have no action.
+        "delete" => "",
+        _ => "completed",
+    };
+    if payload.action != wanted {
         return Ok(reply_ok());
     }
 
-    let Some(deploy_conf) = config.deploy.iter().find(|d| d.repository == repo_full) else {
+    if !config.deploy.iter().any(|d| d.repository == repo_full) {
         eprintln!("! Error: unknown repository {}", repo_full);
         return Ok(reply_error(StatusCode::BAD_REQUEST, "unknown repository"));
+    }
+
+    match event {
+        "release" => Ok(handle_release(config, payload)),
+        "delete" | "pull_request" => Ok(handle_branch_gone(config, payload)),
+        _ => Ok(handle_workflow_run(config, payload)),
+    }
+}
+
+/// A branch was deleted, or its pull request closed (merged or not): removes its
+/// previews from every entry deploying that branch by pattern.
+fn handle_branch_gone(config: &'static config::Config, payload: Payload) -> WithStatus<warp::reply::Json> {
+    let repo_full = payload.repository.full_name;
+
+    let branch = if let Some(pull_request) = payload.pull_request {
+        // A fork's branch of the same name is not ours to clean up.
+        if pull_request.head.repo.is_none_or(|repo| repo.full_name != repo_full) {
+            println!("> Ignoring closed pull request of {}: its branch lives in a fork.", repo_full);
+            return reply_ok();
+        }
+        pull_request.head.git_ref
+    } else {
+        match (payload.ref_type.as_deref(), payload.git_ref) {
+            (Some("branch"), Some(branch)) => branch,
+            _ => return reply_ok(),
+        }
     };
 
+    let claims = match claim(config, payload.delivery.as_deref(), Vec::new()) {
+        Ok(claims) => claims,
+        Err(duplicate) => return duplicate,
+    };
+
+    for deploy_conf in config.deploy.iter().filter(|d| d.repository == repo_full) {
+        if !deploy_conf.deploys_previews() || !deploy_conf.branch_matches(Some(&branch)) {
+            continue;
+        }
+        println!("> Removing the {} preview of {}.", branch, repo_full);
+        let branch = branch.clone();
+        let claims = claims.clone();
+        tokio::spawn(async move {
+            // Never delete a preview a deploy is still writing.
+            let _guard = deploy_conf.queue.lock().await;
+            if let Err(e) = deploy::remove_preview(deploy_conf, &branch).await {
+                eprintln!("! Failed to remove the {} preview of {}: {:#}", branch, deploy_conf.repository, e);
+                config.deliveries.release(&claims);
+            }
+        });
+    }
+
+    reply_ok()
+}
+
+fn handle_workflow_run(config: &'static config::Config, payload: Payload) -> WithStatus<warp::reply::Json> {
+    let repo_full = payload.repository.full_name;
+    let repository_url = payload.repository.url.unwrap_or_else(|| github::repository_url(&repo_full));
+
+    let entries: Vec<_> = entries(config, &repo_full, config::Source::WorkflowRun).collect();
+    if entries.is_empty() {
+        println!("> Ignoring run of {}: the entry deploys releases.", repo_full);
+        return reply_ok();
+    }
+
     let Some(run) = payload.workflow_run else {
         eprintln!("! Error: workflow_run event for {} lacks a workflow_run object", repo_full);
-        return Ok(reply_error(StatusCode::BAD_REQUEST, "invalid body"));
+        return reply_error(StatusCode::BAD_REQUEST, "invalid body");
     };
 
     // "completed" is not "succeeded": failed or cancelled runs may still have
     // uploaded artifacts, and those must never deploy.
     if run.conclusion.as_deref() != Some("success") {
         println!("> Ignoring run of {}: conclusion is {:?}.", repo_full, run.conclusion);
-        return Ok(reply_ok());
+        return reply_ok();
     }
 
-    if let Some(want) = &deploy_conf.branch {
-        if run.head_branch.as_deref() != Some(want.as_str()) {
-            println!("> Ignoring run of {}: branch {:?} is not {:?}.", repo_full, run.head_branch, want);
-            return Ok(reply_ok());
-        }
-    }
-
-    if let Some(want) = &deploy_conf.workflow {
-        if run.name.as_deref() != Some(want.as_str()) {
-            println!("> Ignoring run of {}: workflow {:?} is not {:?}.", repo_full, run.name, want);
-            return Ok(reply_ok());
-        }
+    // A repository may have several entries, typically production for `main` and
+    // previews for feature branches: the run deploys through each one it passes.
+    let passing: Vec<_> = entries
+        .into_iter()
+        .filter(|(_, deploy_conf)| {
+            if !deploy_conf.branch_matches(run.head_branch.as_deref()) {
+                println!("> Ignoring run of {} for an entry: branch {:?} doesn't match.", repo_full, run.head_branch);
+                return false;
+            }
+            if !deploy_conf.workflow_matches(run.name.as_deref(), run.path.as_deref()) {
+                println!("> Ignoring run of {} for an entry: workflow {:?} ({:?}) doesn't match.", repo_full, run.name, run.path);
+                return false;
+            }
+            true
+        })
+        .collect();
+    if passing.is_empty() {
+        return reply_ok();
     }
 
+    let position = run.position();
     let Some(artifacts_url) = run.artifacts_url.filter(|u| !u.is_empty()) else {
         eprintln!("! Error: missing artifacts_url for {}", repo_full);
-        return Ok(reply_error(StatusCode::BAD_REQUEST, "missing artifacts_url"));
+        return reply_error(StatusCode::BAD_REQUEST, "missing artifacts_url");
+    };
+
+    let run_key = format!("run:{}:{}:{}", repo_full, run.id, run.run_attempt.unwrap_or(1));
+    let claims = match claim(config, payload.delivery.as_deref(), vec![run_key]) {
+        Ok(claims) => claims,
+        Err(duplicate) => return duplicate,
+    };
+
+    let run = deploy::Run {
+        position,
+        id: run.id,
+        head_sha: run.head_sha,
+        head_branch: run.head_branch,
+        workflow: run.name,
+        tag: None,
+        listing: deploy::Listing::Artifacts(artifacts_url),
+        repository_url,
+        job: None,
+        claims,
+    };
+    for (entry, _) in passing {
+        spawn_deploy(config, entry, run.clone());
+    }
+
+    reply_ok()
+}
+
+fn handle_release(config: &'static config::Config, payload: Payload) -> WithStatus<warp::reply::Json> {
+    let repo_full = payload.repository.full_name;
+    let repository_url = payload.repository.url.unwrap_or_else(|| github::repository_url(&repo_full));
+
+    let entries: Vec<_> = entries(config, &repo_full, config::Source::Release).collect();
+    if entries.is_empty() {
+        println!("> Ignoring release of {}: the entry deploys workflow runs.", repo_full);
+        return reply_ok();
+    }
+
+    let Some(release) = payload.release else {
+        eprintln!("! Error: release event for {} lacks a release object", repo_full);
+        return reply_error(StatusCode::BAD_REQUEST, "invalid body");
+    };
+
+    // `published` also fires for prereleases; those are for testers, not servers.
+    if release.draft || release.prerelease {
+        println!("> Ignoring release {} of {}: draft or prerelease.", release.tag_name, repo_full);
+        return reply_ok();
+    }
+
+    let passing: Vec<_> = entries.into_iter().filter(|(_, deploy_conf)| deploy_conf.tag_matches(&release.tag_name)).collect();
+    if passing.is_empty() {
+        println!("> Ignoring release {} of {}: tag doesn't match.", release.tag_name, repo_full);
+        return reply_ok();
+    }
+
+    let Some(assets_url) = release.assets_url.filter(|u| !u.is_empty()) else {
+        eprintln!("! Error: missing assets_url for {}", repo_full);
+        return reply_error(StatusCode::BAD_REQUEST, "missing assets_url");
+    };
+
+    let release_key = format!("release:{}:{}", repo_full, release.id);
+    let claims = match claim(config, payload.delivery.as_deref(), vec![release_key]) {
+        Ok(claims) => claims,
+        Err(duplicate) => return duplicate,
+    };
+
+    let run = release_run(release.id, release.tag_name, assets_url, repository_url);
+    let run = deploy::Run { claims, ..run };
+    for (entry, _) in passing {
+        spawn_deploy(config, entry, run.clone());
+    }
+
+    reply_ok()
+}
+
+/// Claims the delivery's GUID and `keys` (what it would act on) in the delivery
+/// journal, returning the keys claimed, to release should the work fail, or the
+/// reply to send instead if any of them was handled before: GitHub redelivers, and
+/// two hooks may deliver the same run.
+fn claim(config: &config::Config, delivery: Option<&str>, keys: Vec<String>) -> Result<Vec<String>, WithStatus<warp::reply::Json>> {
+    let keys: Vec<String> = delivery.map(|guid| format!("delivery:{guid}")).into_iter().chain(keys).collect();
+    let Some(duplicate) = config.deliveries.claim(&keys) else {
+        return Ok(keys);
+    };
+    println!("> Ignoring duplicate delivery: {} was already handled.", duplicate);
+    Err(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"error": null, "duplicate": true})),
+        StatusCode::OK,
+    ))
+}
+
+/// The deploy entries of `repository` fed by `source` events, with their index.
+fn entries<'a>(
+    config: &'static config::Config,
+    repository: &'a str,
+    source: config::Source,
+) -> impl Iterator<Item = (usize, &'static config::Deploy)> + 'a {
+    config.deploy.iter().enumerate().filter(move |(_, d)| d.repository == repository && d.source == source)
+}
+
+fn release_run(id: u64, tag: String, assets_url: String, repository_url: String) -> deploy::Run {
+    deploy::Run {
+        id,
+        tag: Some(tag),
+        listing: deploy::Listing::ReleaseAssets(assets_url),
+        repository_url,
+        ..Default::default()
+    }
+}
+
+/// Redeploys the latest successful run (or latest release) of an entry, as if its
+/// webhook had just been delivered: recovery after wiping a server or fixing a
+/// broken target.
+async fn handle_redeploy(
+    owner: String,
+    repo: String,
+    config: &'static config::Config,
+    headers: HeaderMap,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Some(reply) = refuse_if_draining(config) {
+        return Ok(reply);
+    }
+    if let Err(e) = signature::verify_admin(config, &headers) {
+        eprintln!("! Error: invalid admin credential: {}", e);
+        return Ok(reply_error(StatusCode::FORBIDDEN, "invalid credential"));
+    }
+
+    let repo_full = format!("{owner}/{repo}");
+    let Some((entry, deploy_conf)) = config.main_entry(&repo_full) else {
+        return Ok(reply_error(StatusCode::NOT_FOUND, "unknown repository"));
     };
 
-    let token = config.credential.github_token.clone();
+    let token = config.github_token_for(deploy_conf);
+    let run = match deploy_conf.source {
+        config::Source::WorkflowRun => github::latest_successful_run(token, deploy_conf).await.map(|run| deploy::Run {
+            position: run.position(),
+            id: run.id,
+            head_sha: run.head_sha,
+            head_branch: run.head_branch,
+            workflow: run.name,
+            tag: None,
+            listing: deploy::Listing::Artifacts(run.artifacts_url.unwrap_or_default()),
+            repository_url: github::repository_url(&repo_full),
+            job: None,
+            claims: Vec::new(),
+        }),
+        config::Source::Release => github::latest_release(token, deploy_conf).await.map(|release| {
+            let assets_url = release.assets_url.unwrap_or_default();
+            release_run(release.id, release.tag_name, assets_url, github::repository_url(&repo_full))
+        }),
+    };
+    let run = match run {
+        Ok(run) => run,
+        Err(e) => {
+            eprintln!("! Error: nothing to redeploy for {}: {:#}", repo_full, e);
+            return Ok(reply_error(StatusCode::BAD_GATEWAY, &format!("{e:#}")));
+        }
+    };
+    println!("Redeploy requested: {} run {}", repo_full, run.id);
+
+    let run_id = run.id;
+    spawn_deploy(config, entry, run);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"error": null, "run_id": run_id})),
+        StatusCode::OK,
+    ))
+}
+
+/// Queues `run` to deploy in the background once the entry's running deploy is done,
+/// unless a newer run supersedes it meanwhile; the webhook reply doesn't wait, but
+/// the run is journaled first so a restart doesn't lose it.
+fn spawn_deploy(config: &'static config::Config, entry: usize, mut run: deploy::Run) {
+    let deploy_conf = &config.deploy[entry];
+    if run.job.is_none() {
+        run.job = config.jobs.accept(entry, deploy_conf, &run);
+    }
+    let key = deploy::live_key(deploy_conf, &run);
+    if let Some(job) = deploy_conf.queue.push(key.clone(), run).and_then(|dropped| dropped.job) {
+        config.jobs.finish(job);
+    }
+
     tokio::spawn(async move {
-        // One deploy at a time per entry: a second run completing mid-deploy would
-        // otherwise race extraction into the same target directories.
-        let _guard = deploy_conf.lock.lock().await;
-        if let Err(e) = download::download_artifacts(&token, &repo_full, &artifacts_url, &deploy_conf.artifact).await {
-            eprintln!("! Failed to deploy artifacts for {}: {:#}", repo_full, e);
+        let _guard = deploy_conf.queue.lock().await;
+        if config.draining.load(Ordering::SeqCst) {
+            // Left pending, and journaled, for the next start.
+            return;
+        }
+        // Every queued run gets a turn, but only the one still pending deploys: the
+        // turns of dropped runs find nothing left.
+        let Some(run) = deploy_conf.queue.take(&key) else {
+            return;
+        };
+        if let Some(job) = run.job {
+            config.jobs.start(job, entry, deploy_conf, &run);
+        }
+        if let Err(e) = deploy::run(config, deploy_conf, &run).await {
+            eprintln!("! Failed to deploy artifacts for {}: {:#}", deploy_conf.repository, e);
+            config.deliveries.release(&run.claims);
+        }
+        if let Some(job) = run.job {
+            config.jobs.finish(job);
         }
     });
+}
+
+/// Deploys again what the job journal holds from before a restart: queued runs,
+/// and runs cut short midway, whose leftovers are cleaned up first.
+fn resume_jobs(config: &'static config::Config) -> anyhow::Result<()> {
+    for job in config.jobs.unfinished().context("failed to read the job journal")? {
+        // Entries are told apart by their index; one that now holds another
+        // repository or source means the config changed under the journal.
+        let deploy_conf = config.deploy.get(job.entry).filter(|d| d.repository == job.repository && d.source == job.source);
+        let Some(deploy_conf) = deploy_conf else {
+            eprintln!("! Warning: dropping the journaled deploy of run {} of {}: no such entry anymore.", job.run.id, job.repository);
+            if let Some(id) = job.run.job {
+                config.jobs.finish(id);
+            }
+            continue;
+        };
+        if job.started {
+            println!("> Deploy of run {} of {} was interrupted; cleaning up and deploying it again.", job.run.id, job.repository);
+            deploy::clean_interrupted(deploy_conf, &job.run);
+        } else {
+            println!("> Resuming the queued deploy of run {} of {}.", job.run.id, job.repository);
+        }
+        spawn_deploy(config, job.entry, job.run);
+    }
+    Ok(())
+}
+
+async fn handle_rollback(
+    owner: String,
+    repo: String,
+    config: &'static config::Config,
+    headers: HeaderMap,
+    query: RollbackQuery,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Some(reply) = refuse_if_draining(config) {
+        return Ok(reply);
+    }
+    if let Err(e) = signature::verify_admin(config, &headers) {
+        eprintln!("! Error: invalid admin credential: {}", e);
+        return Ok(reply_error(StatusCode::FORBIDDEN, "invalid credential"));
+    }
+
+    let repo_full = format!("{owner}/{repo}");
+    let Some((_, deploy_conf)) = config.main_entry(&repo_full) else {
+        return Ok(reply_error(StatusCode::NOT_FOUND, "unknown repository"));
+    };
+
+    println!("Rollback requested: {} to {}", repo_full, query.to.as_deref().unwrap_or("previous version"));
 
-    Ok(reply_ok())
+    // Never swap directories out from under a running deploy of the same entry.
+    let _guard = deploy_conf.queue.lock().await;
+    match deploy::rollback(deploy_conf, query.to.as_deref()).await {
+        Ok(versions) => {
+            let versions: serde_json::Map<String, serde_json::Value> =
+                versions.into_iter().map(|(name, version)| (name, version.into())).collect();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": null, "versions": versions})),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            eprintln!("! Failed to roll back {}: {:#}", repo_full, e);
+            Ok(reply_error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e:#}")))
+        }
+    }
+}
+
+/// Last attempted and last successful deploy of every entry, and any rollback
+/// since, in config order.
+fn status_json(config: &config::Config) -> serde_json::Value {
+    config
+        .deploy
+        .iter()
+        .map(|deploy_conf| {
+            let status = deploy_conf.status.lock().unwrap_or_else(PoisonError::into_inner).clone();
+            serde_json::json!({
+                "repository": deploy_conf.repository,
+                "last_attempt": status.last_attempt,
+                "last_success": status.last_success,
+                "rolled_back": status.rolled_back,
+            })
+        })
+        .collect()
 }
 
 fn reply_ok() -> WithStatus<warp::reply::Json> {
@@ -177,25 +654,38 @@ mod tests {
     use hmac::{KeyInit, Mac};
 
     const SECRET: &str = "testsecret";
+    const ADMIN_TOKEN: &str = "testadmintoken";
 
     /// Shared test config; `&TEST_CONFIG` derefs to the `&'static config::Config`
     /// that `handle_github` expects.
     static TEST_CONFIG: LazyLock<config::Config> = LazyLock::new(|| config::Config {
         credential: config::Credential {
-            github_webhook_secret: SECRET.to_owned(),
-            github_token: String::new(),
+            github_webhook_secret: config::WebhookSecrets(vec![SECRET.to_owned()]),
+            admin_token: ADMIN_TOKEN.to_owned(),
+            ..Default::default()
         },
         deploy: vec![config::Deploy {
             repository: "test/repo".to_owned(),
-            branch: Some("main".to_owned()),
-            workflow: Some("CI".to_owned()),
+            branch: Some(config::Patterns::new(vec!["main".to_owned()]).unwrap()),
+            workflow: Some(config::Patterns::new(vec!["CI".to_owned(), ".github/workflows/deploy.yml".to_owned()]).unwrap()),
             artifact: vec![config::Artifact {
                 name: "bundle".to_owned(),
                 target: "unused".to_owned(),
-                preserve: Vec::new(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }, config::Deploy {
+            repository: "test/releases".to_owned(),
+            source: config::Source::Release,
+            tag: Some(config::Patterns::new(vec!["v*".to_owned()]).unwrap()),
+            artifact: vec![config::Artifact {
+                name: "bundle.tar.gz".to_owned(),
+                target: "unused".to_owned(),
+                ..Default::default()
             }],
-            lock: Default::default(),
+            ..Default::default()
         }],
+        ..Default::default()
     });
 
     fn sign(secret: &str, body: &[u8]) -> String {
@@ -234,6 +724,7 @@ mod tests {
     /// A `workflow_run` object passing every content gate, `artifacts_url` absent.
     fn gate_passing_run() -> serde_json::Value {
         serde_json::json!({
+            "id": 1,
             "conclusion": "success",
             "head_branch": "main",
             "name": "CI",
@@ -310,6 +801,18 @@ mod tests {
         assert_eq!(status_for(headers, &body).await, StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn workflow_matches_by_file_too() {
+        // Not named "CI", but defined in a listed workflow file: passes the gate and
+        // falls through to the artifacts_url check.
+        let mut run = gate_passing_run();
+        run["name"] = "Build and deploy".into();
+        run["path"] = ".github/workflows/deploy.yml".into();
+        let body = run_payload("completed", "test/repo", run);
+        let headers = signed_headers("workflow_run", &body);
+        assert_eq!(status_for(headers, &body).await, StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test]
     async fn missing_artifacts_url_is_bad_request() {
         // Key absent entirely.
@@ -325,10 +828,390 @@ mod tests {
         assert_eq!(status_for(headers, &body).await, StatusCode::BAD_REQUEST);
     }
 
+    /// `release` is the `release` object; like `run_payload`, gate tests leave
+    /// `assets_url` out so a regressed gate fails with 400 instead of deploying.
+    fn release_payload(action: &str, repo: &str, release: serde_json::Value) -> Vec<u8> {
+        serde_json::json!({
+            "action": action,
+            "repository": { "full_name": repo },
+            "release": release,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn gate_passing_release() -> serde_json::Value {
+        serde_json::json!({ "id": 9, "tag_name": "v1.0.0", "draft": false, "prerelease": false })
+    }
+
+    #[tokio::test]
+    async fn release_gates() {
+        let body = release_payload("created", "test/releases", gate_passing_release());
+        assert_eq!(status_for(signed_headers("release", &body), &body).await, StatusCode::OK);
+
+        let mut release = gate_passing_release();
+        release["prerelease"] = true.into();
+        let body = release_payload("published", "test/releases", release);
+        assert_eq!(status_for(signed_headers("release", &body), &body).await, StatusCode::OK);
+
+        let mut release = gate_passing_release();
+        release["tag_name"] = "nightly".into();
+        let body = release_payload("published", "test/releases", release);
+        assert_eq!(status_for(signed_headers("release", &body), &body).await, StatusCode::OK);
+
+        // The entry deploys workflow runs, not releases.
+        let body = release_payload("published", "test/repo", gate_passing_release());
+        assert_eq!(status_for(signed_headers("release", &body), &body).await, StatusCode::OK);
+
+        let body = release_payload("published", "unknown/repo", gate_passing_release());
+        assert_eq!(status_for(signed_headers("release", &body), &body).await, StatusCode::BAD_REQUEST);
+
+        let body = release_payload("published", "test/releases", gate_passing_release());
+        assert_eq!(status_for(signed_headers("release", &body), &body).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn workflow_run_of_release_entry_is_ignored() {
+        let body = run_payload("completed", "test/releases", gate_passing_run());
+        let headers = signed_headers("workflow_run", &body);
+        assert_eq!(status_for(headers, &body).await, StatusCode::OK);
+    }
+
     #[tokio::test]
     async fn malformed_json_is_bad_request() {
         let body = b"{ not json";
         let headers = signed_headers("workflow_run", body);
         assert_eq!(status_for(headers, body).await, StatusCode::BAD_REQUEST);
     }
+
+    async fn rollback_status(config: &'static config::Config, repo: &str, authorization: &str) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", authorization.parse().unwrap());
+        let (owner, repo) = repo.split_once('/').unwrap();
+        let query = RollbackQuery { to: None };
+        let reply = handle_rollback(owner.to_owned(), repo.to_owned(), config, headers, query).await.unwrap();
+        warp::reply::Reply::into_response(reply).status()
+    }
+
+    #[tokio::test]
+    async fn rollback_requires_admin_token() {
+        let status = rollback_status(&TEST_CONFIG, "test/repo", "Bearer wrongtoken").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // A valid webhook signature is no substitute.
+        let status = rollback_status(&TEST_CONFIG, "test/repo", &format!("Bearer {SECRET}")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn rollback_of_unknown_repository_is_not_found() {
+        let status = rollback_status(&TEST_CONFIG, "unknown/repo", &format!("Bearer {ADMIN_TOKEN}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rollback_swaps_target_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("app");
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::write(target.join("v.txt"), "v2").unwrap();
+        std::fs::create_dir_all(dir.path().join(".app.old-1")).unwrap();
+        std::fs::write(dir.path().join(".app.old-1").join("v.txt"), "v1").unwrap();
+
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            credential: config::Credential {
+                admin_token: ADMIN_TOKEN.to_owned(),
+                ..Default::default()
+            },
+            deploy: vec![config::Deploy {
+                repository: "test/repo".to_owned(),
+                artifact: vec![config::Artifact {
+                    name: "bundle".to_owned(),
+                    target: target.to_string_lossy().into_owned(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }));
+
+        let status = rollback_status(config, "test/repo", &format!("Bearer {ADMIN_TOKEN}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(std::fs::read_to_string(target.join("v.txt")).unwrap(), "v1");
+        assert!(status_json(config)[0]["rolled_back"]["versions"]["bundle"].is_string());
+    }
+
+    #[tokio::test]
+    async fn duplicate_deliveries_are_ignored() {
+        use std::io::Write;
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        // Serves the run's one artifact, or fails its listing while `broken`.
+        let broken = Arc::new(AtomicBool::new(true));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let (fail, list_base) = (Arc::clone(&broken), base.clone());
+        let list = warp::path("artifacts").map(move || {
+            let body = serde_json::json!({
+                "total_count": 1,
+                "artifacts": [{ "name": "bundle", "archive_download_url": format!("{list_base}/download") }],
+            });
+            let status = if fail.load(Ordering::SeqCst) { 500 } else { 200 };
+            warp::http::Response::builder().status(status).body(body.to_string()).unwrap()
+        });
+        let download = warp::path("download").map(|| {
+            let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            writer.start_file("index.html", zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(b"hello").unwrap();
+            writer.finish().unwrap().into_inner()
+        });
+        tokio::spawn(warp::serve(list.or(download)).incoming(listener).run());
+
+        let dir = tempfile::tempdir().unwrap();
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            credential: config::Credential {
+                github_webhook_secret: config::WebhookSecrets(vec![SECRET.to_owned()]),
+                github_token: "token".to_owned(),
+                ..Default::default()
+            },
+            deploy: vec![config::Deploy {
+                repository: "test/repo".to_owned(),
+                artifact: vec![config::Artifact {
+                    name: "bundle".to_owned(),
+                    target: dir.path().join("site").display().to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            retry: config::Retry { max_attempts: 1, ..Default::default() },
+            ..Default::default()
+        }));
+        let handle = warp::any()
+            .map(move || config)
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .and_then(handle_github);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(warp::serve(handle).incoming(listener).run());
+
+        let deliver = |guid: &'static str, attempt: u64| {
+            let (url, artifacts_url) = (url.clone(), format!("{base}/artifacts"));
+            async move {
+                let mut run = gate_passing_run();
+                run["id"] = 42.into();
+                run["run_attempt"] = attempt.into();
+                run["artifacts_url"] = artifacts_url.into();
+                let body = run_payload("completed", "test/repo", run);
+                let mut headers = signed_headers("workflow_run", &body);
+                headers.insert("X-GitHub-Delivery", guid.parse().unwrap());
+                let reply: serde_json::Value = reqwest::Client::new()
+                    .post(url)
+                    .headers(headers)
+                    .body(body)
+                    .send()
+                    .await
+                    .unwrap()
+                    .json()
+                    .await
+                    .unwrap();
+                reply["duplicate"].as_bool().unwrap_or(false)
+            }
+        };
+        // Whether the deploy started by the last delivery succeeded, once it's done.
+        let deployed = || async {
+            for _ in 0..500 {
+                let finished = config.deploy[0].status.lock().unwrap().last_attempt.take_if(|r| r.finished_at.is_some());
+                if let Some(record) = finished {
+                    return record.error.is_none();
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            panic!("the deploy never finished");
+        };
+
+        assert!(!deliver("guid-1", 1).await);
+        assert!(!deployed().await);
+        // Redelivering a failed deploy retries it.
+        broken.store(false, Ordering::SeqCst);
+        assert!(!deliver("guid-1", 1).await);
+        assert!(deployed().await);
+        // GitHub's redelivery.
+        assert!(deliver("guid-1", 1).await);
+        // A second hook on the same repository.
+        assert!(deliver("guid-2", 1).await);
+        // A re-run deploys again.
+        assert!(!deliver("guid-3", 2).await);
+    }
+
+    #[tokio::test]
+    async fn draining_refuses_deliveries_and_queued_deploys() {
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            credential: config::Credential {
+                github_webhook_secret: config::WebhookSecrets(vec![SECRET.to_owned()]),
+                admin_token: ADMIN_TOKEN.to_owned(),
+                ..Default::default()
+            },
+            deploy: vec![config::Deploy {
+                repository: "test/repo".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }));
+        config.draining.store(true, Ordering::SeqCst);
+
+        let body = run_payload("completed", "test/repo", gate_passing_run());
+        let reply = handle_github(config, signed_headers("workflow_run", &body), body.into()).await.unwrap();
+        assert_eq!(warp::reply::Reply::into_response(reply).status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A deploy queued before the signal never starts: no attempt is recorded.
+        let deploy_conf = &config.deploy[0];
+        spawn_deploy(config, 0, deploy::Run { id: 5, ..Default::default() });
+        // Lets the single-threaded test runtime run the deploy's turn.
+        tokio::task::yield_now().await;
+        drop(deploy_conf.queue.lock().await);
+        assert!(deploy_conf.status.lock().unwrap().last_attempt.is_none());
+        assert_eq!(deploy_conf.queue.take("").map(|run| run.id), Some(5));
+    }
+
+    #[tokio::test]
+    async fn runs_deploy_through_every_entry_they_pass() {
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            credential: config::Credential {
+                github_webhook_secret: config::WebhookSecrets(vec![SECRET.to_owned()]),
+                ..Default::default()
+            },
+            deploy: vec![
+                config::Deploy {
+                    repository: "test/repo".to_owned(),
+                    branch: Some(config::Patterns::new(vec!["main".to_owned(), "feature/shared".to_owned()]).unwrap()),
+                    ..Default::default()
+                },
+                config::Deploy {
+                    repository: "test/repo".to_owned(),
+                    branch: Some(config::Patterns::new(vec!["feature/*".to_owned()]).unwrap()),
+                    previews: true,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }));
+        let (production, previews) = (&config.deploy[0], &config.deploy[1]);
+        // Holding both entries keeps the queued runs where the test can see them.
+        let _production = production.queue.lock().await;
+        let _previews = previews.queue.lock().await;
+
+        let deliver = |id: u64, branch: &str| {
+            let run = serde_json::json!({
+                "id": id,
+                "conclusion": "success",
+                "head_branch": branch,
+                "name": "CI",
+                "artifacts_url": "http://127.0.0.1:9/artifacts",
+            });
+            let body = run_payload("completed", "test/repo", run);
+            async move {
+                let reply = handle_github(config, signed_headers("workflow_run", &body), body.into()).await.unwrap();
+                assert_eq!(warp::reply::Reply::into_response(reply).status(), StatusCode::OK);
+            }
+        };
+
+        deliver(1, "main").await;
+        deliver(2, "feature/login").await;
+        deliver(3, "feature/shared").await;
+        deliver(4, "fix/typo").await;
+
+        assert_eq!(production.queue.take("").map(|run| run.id), Some(3));
+        assert_eq!(previews.queue.take("feature/login").map(|run| run.id), Some(2));
+        assert_eq!(previews.queue.take("feature/shared").map(|run| run.id), Some(3));
+        assert!(previews.queue.take("main").is_none() && previews.queue.take("fix/typo").is_none());
+    }
+
+    #[tokio::test]
+    async fn branch_gone_removes_its_preview() {
+        let dir = tempfile::tempdir().unwrap();
+        let previews = dir.path().join("previews");
+        let (gone, fork) = (pattern::slug("feature/X"), pattern::slug("feature/y"));
+        // `feature-x` is another branch's preview, however alike the names.
+        for name in [gone.clone(), format!(".{gone}.old-1"), fork.clone(), "feature-x".to_owned()] {
+            std::fs::create_dir_all(previews.join(name)).unwrap();
+        }
+
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            credential: config::Credential {
+                github_webhook_secret: config::WebhookSecrets(vec![SECRET.to_owned()]),
+                ..Default::default()
+            },
+            deploy: vec![config::Deploy {
+                repository: "test/repo".to_owned(),
+                branch: Some(config::Patterns::new(vec!["feature/*".to_owned()]).unwrap()),
+                previews: true,
+                artifact: vec![config::Artifact {
+                    name: "site".to_owned(),
+                    target: format!("{}/{{branch_slug}}", previews.display()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }));
+        let deliver = |event: &'static str, body: serde_json::Value| async move {
+            let body = body.to_string().into_bytes();
+            let reply = handle_github(config, signed_headers(event, &body), body.into()).await.unwrap();
+            warp::reply::Reply::into_response(reply).status()
+        };
+
+        // A fork's `feature/y` is someone else's branch.
+        let fork_closed = serde_json::json!({
+            "action": "closed",
+            "repository": { "full_name": "test/repo" },
+            "pull_request": { "head": { "ref": "feature/y", "repo": { "full_name": "fork/repo" } } },
+        });
+        assert_eq!(deliver("pull_request", fork_closed).await, StatusCode::OK);
+
+        let deleted = serde_json::json!({
+            "ref": "feature/X",
+            "ref_type": "branch",
+            "repository": { "full_name": "test/repo" },
+        });
+        assert_eq!(deliver("delete", deleted).await, StatusCode::OK);
+
+        for _ in 0..500 {
+            if !previews.join(&gone).exists() && !previews.join(format!(".{gone}.old-1")).exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!previews.join(&gone).exists());
+        assert!(!previews.join(format!(".{gone}.old-1")).exists());
+        assert!(previews.join(fork).exists());
+        assert!(previews.join("feature-x").exists());
+    }
+
+    async fn redeploy_status(repo: &str, authorization: &str) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", authorization.parse().unwrap());
+        let (owner, repo) = repo.split_once('/').unwrap();
+        let reply = handle_redeploy(owner.to_owned(), repo.to_owned(), &TEST_CONFIG, headers).await.unwrap();
+        warp::reply::Reply::into_response(reply).status()
+    }
+
+    #[tokio::test]
+    async fn redeploy_requires_admin_token() {
+        assert_eq!(redeploy_status("test/repo", "Bearer wrongtoken").await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn redeploy_of_unknown_repository_is_not_found() {
+        let status = redeploy_status("unknown/repo", &format!("Bearer {ADMIN_TOKEN}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn status_lists_every_entry() {
+        let status = status_json(&TEST_CONFIG);
+        assert_eq!(status[0]["repository"], "test/repo");
+        assert!(status[0]["last_success"].is_null());
+        assert!(status[0]["rolled_back"].is_null());
+    }
 }
@@ -0,0 +1,196 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::config::{self, NotifyEvent, NotifyKind};
+use crate::deploy;
+use crate::download::CLIENT;
+
+/// Discord rejects messages over 2000 characters; long error chains are cut to fit.
+const MAX_ERROR_CHARS: usize = 1500;
+
+/// Per-request limit: notifications are sent while the entry's deploys wait their
+/// turn, so a slow webhook must not hold them up for the client's read timeout.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum Event<'a> {
+    Start,
+    Success { duration: Duration },
+    Failure { duration: Duration, error: &'a str },
+}
+
+impl Event<'_> {
+    fn kind(&self) -> NotifyEvent {
+        match self {
+            Self::Start => NotifyEvent::Start,
+            Self::Success { .. } => NotifyEvent::Success,
+            Self::Failure { .. } => NotifyEvent::Failure,
+        }
+    }
+}
+
+/// Sends `event` to every global and per-entry `notify` target subscribed to it.
+/// Best effort: a failed notification is logged and never fails the deploy.
+pub async fn send(config: &config::Config, deploy_conf: &config::Deploy, run: &deploy::Run, event: &Event<'_>) {
+    for target in config.notify.iter().chain(&deploy_conf.notify) {
+        if !target.events.contains(&event.kind()) {
+            continue;
+        }
+
+        let body = match target.kind {
+            NotifyKind::Discord => json!({ "content": message(deploy_conf, run, event) }),
+            NotifyKind::Slack => json!({ "text": message(deploy_conf, run, event) }),
+            NotifyKind::Webhook => generic_body(deploy_conf, run, event),
+        };
+
+        let sent = CLIENT
+            .post(&target.url)
+            .timeout(TIMEOUT)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = sent {
+            // Chat webhook URLs are credentials: never log them.
+            let e = e.without_url();
+            eprintln!("! Warning: failed to send {:?} notification for {}: {}", target.kind, deploy_conf.repository, e);
+        }
+    }
+}
+
+/// One human-readable line for chat backends.
+fn message(deploy_conf: &config::Deploy, run: &deploy::Run, event: &Event<'_>) -> String {
+    let what = match &run.tag {
+        Some(tag) => format!("{} release {}", deploy_conf.repository, tag),
+        None => format!(
+            "{} {}@{} (run {})",
+            deploy_conf.repository,
+            run.head_branch.as_deref().unwrap_or("?"),
+            short_sha(run),
+            run.id
+        ),
+    };
+    match event {
+        Event::Start => format!("Deploying {what}..."),
+        Event::Success { duration } => format!("Deployed {what} in {}s.", duration.as_secs()),
+        Event::Failure { duration, error } => {
+            let error = match error.char_indices().nth(MAX_ERROR_CHARS) {
+                Some((cut, _)) => format!("{}…", &error[..cut]),
+                None => error.to_string(),
+            };
+            format!("Failed to deploy {what} after {}s: {error}", duration.as_secs())
+        }
+    }
+}
+
+fn generic_body(deploy_conf: &config::Deploy, run: &deploy::Run, event: &Event<'_>) -> serde_json::Value {
+    let (duration, error) = match event {
+        Event::Start => (None, None),
+        Event::Success { duration } => (Some(duration.as_secs_f64()), None),
+        Event::Failure { duration, error } => (Some(duration.as_secs_f64()), Some(*error)),
+    };
+    json!({
+        "event": event.kind(),
+        "repository": deploy_conf.repository,
+        "branch": run.head_branch,
+        "workflow": run.workflow,
+        "run_id": run.id,
+        "head_sha": run.head_sha,
+        "tag": run.tag,
+        "duration_secs": duration,
+        "error": error,
+    })
+}
+
+fn short_sha(run: &deploy::Run) -> &str {
+    let sha = run.head_sha.as_deref().unwrap_or("?");
+    sha.get(..7).unwrap_or(sha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    /// Starts a local server recording the JSON bodies POSTed to it.
+    async fn receiver() -> (SocketAddr, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let route = warp::post().and(warp::body::json()).map(move |body: serde_json::Value| {
+            tx.send(body).unwrap();
+            "ok"
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(warp::serve(route).incoming(listener).run());
+        (addr, rx)
+    }
+
+    fn target(kind: NotifyKind, addr: SocketAddr, events: &[NotifyEvent]) -> config::Notify {
+        config::Notify {
+            kind,
+            url: format!("http://{addr}/hook"),
+            events: events.to_vec(),
+        }
+    }
+
+    fn test_run() -> deploy::Run {
+        deploy::Run {
+            id: 42,
+            head_sha: Some("0123456789abcdef".to_owned()),
+            head_branch: Some("main".to_owned()),
+            workflow: Some("CI".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_backends_get_one_line_with_error_chain() {
+        let (addr, mut rx) = receiver().await;
+        let config = config::Config {
+            notify: vec![target(NotifyKind::Discord, addr, &[NotifyEvent::Failure])],
+            ..Default::default()
+        };
+        let deploy_conf = config::Deploy {
+            repository: "test/repo".to_owned(),
+            notify: vec![target(NotifyKind::Slack, addr, &[NotifyEvent::Failure])],
+            ..Default::default()
+        };
+        let event = Event::Failure {
+            duration: Duration::from_secs(3),
+            error: "failed to deploy artifact bundle: disk full",
+        };
+
+        send(&config, &deploy_conf, &test_run(), &event).await;
+
+        let expected = "Failed to deploy test/repo main@0123456 (run 42) after 3s: failed to deploy artifact bundle: disk full";
+        assert_eq!(rx.recv().await.unwrap(), json!({ "content": expected }), "global Discord target");
+        assert_eq!(rx.recv().await.unwrap(), json!({ "text": expected }), "per-entry Slack target");
+    }
+
+    #[tokio::test]
+    async fn generic_webhook_gets_structured_event_and_filters_events() {
+        let (addr, mut rx) = receiver().await;
+        let config = config::Config::default();
+        let deploy_conf = config::Deploy {
+            repository: "test/repo".to_owned(),
+            notify: vec![target(NotifyKind::Webhook, addr, &[NotifyEvent::Success])],
+            ..Default::default()
+        };
+
+        // Not subscribed: nothing may arrive for it.
+        send(&config, &deploy_conf, &test_run(), &Event::Start).await;
+        send(&config, &deploy_conf, &test_run(), &Event::Success { duration: Duration::from_millis(1500) }).await;
+
+        let body = rx.recv().await.unwrap();
+        assert_eq!(body["event"], "success");
+        assert_eq!(body["repository"], "test/repo");
+        assert_eq!(body["run_id"], 42);
+        assert_eq!(body["head_sha"], "0123456789abcdef");
+        assert_eq!(body["duration_secs"], 1.5);
+        assert!(body["error"].is_null());
+        assert!(rx.try_recv().is_err(), "the start event must have been filtered out");
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+/// How an artifact's configured `name` picks artifacts (or release assets): exactly,
+/// by glob (`site-*`: `*`, `?` and `[...]`, each wildcard a capture), or by regex
+/// written between slashes (`/^site-(?P<locale>[a-z]+)$/`).
+#[derive(Debug)]
+pub enum NamePattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl NamePattern {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        if let Some(regex) = name.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+            let regex = Regex::new(regex).with_context(|| format!("invalid artifact name regex {name:?}"))?;
+            return Ok(Self::Regex(regex));
+        }
+        if !name.contains(['*', '?', '[']) {
+            return Ok(Self::Exact(name.to_string()));
+        }
+
+        let mut regex = String::from("^");
+        let mut chars = name.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => regex.push_str("(.*)"),
+                '?' => regex.push_str("(.)"),
+                '[' => {
+                    let mut class = String::from("([");
+                    let mut closed = false;
+                    for (i, c) in chars.by_ref().enumerate() {
+                        match c {
+                            '!' if i == 0 => class.push('^'),
+                            ']' if i > 0 => {
+                                closed = true;
+                                break;
+                            }
+                            '\\' | '[' | ']' => {
+                                class.push('\\');
+                                class.push(c);
+                            }
+                            c => class.push(c),
+                        }
+                    }
+                    if !closed {
+                        bail!("invalid artifact name glob {name:?}: unclosed `[`");
+                    }
+                    class.push_str("])");
+                    regex.push_str(&class);
+                }
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Ok(Self::Regex(Regex::new(&regex).with_context(|| format!("invalid artifact name glob {name:?}"))?))
+    }
+
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Self::Exact(_))
+    }
+
+    /// The template variables a match of `name` yields, or `None` if it doesn't
+    /// match: `{name}` (the whole name), `{capture}` (the first capture), `{1}`,
+    /// `{2}`, ... and, for a regex, its named groups.
+    pub fn captures(&self, name: &str) -> Option<HashMap<String, String>> {
+        let mut vars = HashMap::from([("name".to_string(), name.to_string())]);
+        match self {
+            Self::Exact(exact) => (exact == name).then_some(vars),
+            Self::Regex(regex) => {
+                let captures = regex.captures(name)?;
+                for (i, group) in captures.iter().enumerate().skip(1) {
+                    vars.insert(i.to_string(), group.map_or("", |m| m.as_str()).to_string());
+                }
+                for group in regex.capture_names().flatten() {
+                    vars.insert(group.to_string(), captures.name(group).map_or("", |m| m.as_str()).to_string());
+                }
+                if let Some(first) = captures.get(1) {
+                    vars.insert("capture".to_string(), first.as_str().to_string());
+                }
+                Some(vars)
+            }
+        }
+    }
+
+    /// The capture variables a match may yield; see `captures`.
+    pub fn variables(&self) -> Vec<String> {
+        let mut vars = vec!["name".to_string()];
+        if let Self::Regex(regex) = self {
+            let groups = regex.captures_len() - 1;
+            if groups > 0 {
+                vars.push("capture".to_string());
+            }
+            vars.extend((1..=groups).map(|i| i.to_string()));
+            vars.extend(regex.capture_names().flatten().map(str::to_string));
+        }
+        vars
+    }
+}
+
+/// The part of `template` that only depends on the branch: its path up to the first
+/// component using `{branch}` or `{branch_slug}`, rendered from `vars`. `None` if
+/// the template doesn't depend on the branch. Removing a branch's preview removes
+/// this directory.
+pub fn branch_root(template: &str, vars: &HashMap<String, String>) -> anyhow::Result<Option<String>> {
+    let components: Vec<&str> = template.split('/').collect();
+    let Some(last) = components.iter().position(|c| c.contains("{branch}") || c.contains("{branch_slug}")) else {
+        return Ok(None);
+    };
+    render(&components[..=last].join("/"), vars).map(Some)
+}
+
+/// The `{placeholder}` names in `template`.
+pub fn placeholders(template: &str) -> anyhow::Result<Vec<&str>> {
+    let mut found = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unclosed `{{` in {template:?}");
+        };
+        found.push(&rest[start + 1..start + len]);
+        rest = &rest[start + len + 1..];
+    }
+    Ok(found)
+}
+
+/// Fills in `template`'s placeholders from `vars`. Each value is made path-safe
+/// first (see `path_safe`): a branch like `feature/x` must not add a directory
+/// level, let alone climb out with `..`.
+pub fn render(template: &str, vars: &HashMap<String, String>) -> anyhow::Result<String> {
+    let mut rendered = template.to_string();
+    for name in placeholders(template)? {
+        let value = vars.get(name).with_context(|| format!("no value for {{{name}}} in {template:?}"))?;
+        let value = path_safe(value);
+        if value.is_empty() {
+            bail!("empty value for {{{name}}} in {template:?}");
+        }
+        rendered = rendered.replace(&format!("{{{name}}}"), &value);
+    }
+    Ok(rendered)
+}
+
+/// `value` as a single path component: characters other than ASCII alphanumerics
+/// and `._-` become `-`, and leading dots are dropped (no `..`, no hidden files).
+pub fn path_safe(value: &str) -> String {
+    let safe: String = value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '-' })
+        .collect();
+    safe.trim_start_matches('.').to_string()
+}
+
+/// `value` as a lowercase DNS-label-like slug: runs of anything but ASCII
+/// alphanumerics become a single `-`, trimmed at both ends, at most 63 characters.
+/// Unless that leaves `value` as it was, a short hash of it is appended, so two
+/// branches never share a slug (`Feature/Login_Page` becomes
+/// `feature-login-page-` and 8 hex digits; `feature-login-page` stays itself).
+pub fn slug(value: &str) -> String {
+    let mut slug = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(63);
+    let slug = slug.trim_end_matches('-');
+    if slug == value {
+        return slug.to_string();
+    }
+    let mut base: String = slug.chars().take(63 - 9).collect();
+    base.truncate(base.trim_end_matches('-').len());
+    with_hash(&base, value)
+}
+
+/// A branch name as a single path component: `path_safe`, with a short hash of the
+/// name appended if that changed it, so that `feature/x` and `feature-x` don't
+/// deploy to the same directory.
+pub fn branch_component(branch: &str) -> String {
+    match path_safe(branch) {
+        safe if safe == branch => safe,
+        safe => with_hash(&safe, branch),
+    }
+}
+
+/// `base`, then `-` and the first 8 hex digits of the SHA-256 of `original`.
+fn with_hash(base: &str, original: &str) -> String {
+    let hash = hex::encode(&Sha256::digest(original.as_bytes())[..4]);
+    if base.is_empty() {
+        hash
+    } else {
+        format!("{base}-{hash}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_names_match_only_themselves() {
+        let pattern = NamePattern::parse("site.zip").unwrap();
+        assert!(pattern.is_exact());
+        assert!(pattern.captures("site.zip").is_some());
+        assert!(pattern.captures("site-zip").is_none());
+    }
+
+    #[test]
+    fn glob_wildcards_are_captures() {
+        let pattern = NamePattern::parse("site-*.[!x]ip").unwrap();
+        let vars = pattern.captures("site-ko.zip").unwrap();
+        assert_eq!(vars["capture"], "ko");
+        assert_eq!(vars["1"], "ko");
+        assert_eq!(vars["2"], "z");
+        assert_eq!(vars["name"], "site-ko.zip");
+        assert!(pattern.captures("site-ko.xip").is_none());
+        assert!(pattern.captures("other-ko.zip").is_none());
+        assert!(NamePattern::parse("site-[a").is_err());
+    }
+
+    #[test]
+    fn regex_named_groups_are_variables() {
+        let pattern = NamePattern::parse("/^site-(?P<locale>[a-z]+)$/").unwrap();
+        assert_eq!(pattern.captures("site-en").unwrap()["locale"], "en");
+        assert!(pattern.captures("site-EN").is_none());
+        assert_eq!(pattern.variables(), ["name", "capture", "1", "locale"]);
+    }
+
+    #[test]
+    fn render_makes_values_path_safe() {
+        let vars = HashMap::from([
+            ("branch".to_string(), "feature/../x".to_string()),
+            ("capture".to_string(), "ko".to_string()),
+        ]);
+        assert_eq!(render("/var/www/{capture}/{branch}", &vars).unwrap(), "/var/www/ko/feature-..-x");
+        assert!(render("/var/www/{locale}", &vars).is_err());
+        let dots = HashMap::from([("tag".to_string(), "..".to_string())]);
+        assert!(render("/var/www/{tag}", &dots).is_err());
+        assert!(placeholders("/var/www/{oops").is_err());
+    }
+
+    #[test]
+    fn branch_slug_and_root() {
+        assert_eq!(slug("feature-login-page"), "feature-login-page");
+        let slugs = ["feature/x", "Feature/X", "feature-x", "feature_x"].map(slug);
+        assert!(slugs[0].starts_with("feature-x-") && slugs[0].len() == "feature-x-".len() + 8, "{slugs:?}");
+        assert_eq!(slugs[2], "feature-x");
+        assert_eq!(slugs.iter().collect::<std::collections::HashSet<_>>().len(), 4, "{slugs:?}");
+        assert_eq!(slug(&"x".repeat(100)).len(), 63);
+        assert_eq!(slug("///").len(), 8);
+
+        assert_eq!(branch_component("feature-x"), "feature-x");
+        assert_ne!(branch_component("feature/x"), "feature-x");
+        assert!(branch_component("feature/x").starts_with("feature-x-"));
+
+        let vars = HashMap::from([("branch_slug".to_string(), "feature-x".to_string())]);
+        let root = branch_root("/var/www/previews/{branch_slug}/{capture}", &vars).unwrap();
+        assert_eq!(root.as_deref(), Some("/var/www/previews/feature-x"));
+        assert_eq!(branch_root("/var/www/site", &vars).unwrap(), None);
+    }
+}
@@ -1,4 +1,6 @@
 use hmac::{KeyInit, Mac};
+use serde::Deserialize;
+use sha2::Digest;
 use warp::http::HeaderMap;
 
 use crate::config;
@@ -9,6 +11,9 @@ pub enum VerifyError {
     MissingSignature,
     MalformedSignature,
     SignatureMismatch,
+    EmptyAdminToken,
+    MissingToken,
+    TokenMismatch,
 }
 
 impl std::fmt::Display for VerifyError {
@@ -18,15 +23,49 @@ impl std::fmt::Display for VerifyError {
             Self::MissingSignature => "missing X-Hub-Signature-256 header",
             Self::MalformedSignature => "malformed X-Hub-Signature-256 header",
             Self::SignatureMismatch => "signature mismatch",
+            Self::EmptyAdminToken => "no admin token is configured",
+            Self::MissingToken => "missing bearer token",
+            Self::TokenMismatch => "token mismatch",
         })
     }
 }
 
 impl std::error::Error for VerifyError {}
 
-pub fn verify(config: &config::Config, headers: &HeaderMap, body: &[u8]) -> Result<(), VerifyError> {
-    let secret = config.credential.github_webhook_secret.as_bytes();
-    if secret.is_empty() {
+/// Just enough of any webhook payload to pick the secret.
+#[derive(Deserialize)]
+struct Claimed {
+    repository: Option<ClaimedRepository>,
+}
+
+#[derive(Deserialize)]
+struct ClaimedRepository {
+    full_name: String,
+}
+
+/// Which of the configured secrets a delivery was signed with, for the log while a
+/// rotation is in progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Matched {
+    pub index: usize,
+    pub of: usize,
+}
+
+/// Verifies the delivery's signature against the secrets of the repository the payload
+/// names, accepting any of them. That name is read before it is authenticated, so it
+/// is used for nothing but the lookup: a forged name only selects secrets the forger
+/// must then match. An unparsable body or one without a repository falls back to the
+/// global secrets.
+pub fn verify(config: &config::Config, headers: &HeaderMap, body: &[u8]) -> Result<Matched, VerifyError> {
+    let claimed = serde_json::from_slice::<Claimed>(body)
+        .ok()
+        .and_then(|c| c.repository)
+        .map(|r| r.full_name);
+    let secrets = match &claimed {
+        Some(repository) => config.webhook_secrets_for(repository),
+        None => &config.credential.github_webhook_secret.0,
+    };
+    if secrets.is_empty() || secrets.iter().any(String::is_empty) {
         return Err(VerifyError::EmptySecret);
     }
 
@@ -40,11 +79,45 @@ pub fn verify(config: &config::Config, headers: &HeaderMap, body: &[u8]) -> Resu
     let sig = hex::decode(sig_hex).map_err(|_| VerifyError::MalformedSignature)?;
 
     type HmacSha256 = hmac::Hmac<sha2::Sha256>;
-    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
-    mac.update(body);
+    let index = secrets
+        .iter()
+        .position(|secret| {
+            let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+            mac.update(body);
+            // `verify_slice` is a constant-time comparison.
+            mac.verify_slice(&sig).is_ok()
+        })
+        .ok_or(VerifyError::SignatureMismatch)?;
 
-    // `verify_slice` is a constant-time comparison.
-    mac.verify_slice(&sig).map_err(|_| VerifyError::SignatureMismatch)
+    Ok(Matched { index, of: secrets.len() })
+}
+
+/// Checks the `Authorization: Bearer` header of a manual operation request against
+/// `credential.admin_token`.
+pub fn verify_admin(config: &config::Config, headers: &HeaderMap) -> Result<(), VerifyError> {
+    let expected = config.credential.admin_token.as_bytes();
+    if expected.is_empty() {
+        return Err(VerifyError::EmptyAdminToken);
+    }
+
+    let Some(token) = headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return Err(VerifyError::MissingToken);
+    };
+
+    // Compare digests so neither the comparison time nor an early length check
+    // leaks anything about the configured token.
+    let expected = sha2::Sha256::digest(expected);
+    let actual = sha2::Sha256::digest(token.as_bytes());
+    let diff = expected.iter().zip(actual.iter()).fold(0, |acc, (a, b)| acc | (a ^ b));
+    if diff == 0 {
+        Ok(())
+    } else {
+        Err(VerifyError::TokenMismatch)
+    }
 }
 
 #[cfg(test)]
@@ -54,14 +127,18 @@ mod tests {
 
     const SECRET: &str = "test-webhook-secret";
     const BODY: &[u8] = br#"{"action":"completed"}"#;
+    const ADMIN_TOKEN: &str = "test-admin-token";
+
+    const ONE: Result<Matched, VerifyError> = Ok(Matched { index: 0, of: 1 });
 
     fn make_config(secret: &str) -> config::Config {
         config::Config {
             credential: config::Credential {
-                github_webhook_secret: secret.to_string(),
-                github_token: String::new(),
+                github_webhook_secret: config::WebhookSecrets(vec![secret.to_string()]),
+                admin_token: ADMIN_TOKEN.to_string(),
+                ..Default::default()
             },
-            deploy: Vec::new(),
+            ..Default::default()
         }
     }
 
@@ -87,7 +164,7 @@ mod tests {
         let config = make_config(SECRET);
         let headers =
             headers_with_signature(&format!("sha256={}", sign(SECRET.as_bytes(), BODY)));
-        assert_eq!(verify(&config, &headers, BODY), Ok(()));
+        assert_eq!(verify(&config, &headers, BODY), ONE);
     }
 
     #[test]
@@ -128,4 +205,86 @@ mod tests {
         let headers = headers_with_signature(&format!("sha256={}", sign(b"", BODY)));
         assert_eq!(verify(&config, &headers, BODY), Err(VerifyError::EmptySecret));
     }
+
+    fn headers_with_authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_str(value).expect("header value is visible ascii"));
+        headers
+    }
+
+    #[test]
+    fn admin_accepts_correct_bearer_token() {
+        let config = make_config(SECRET);
+        let headers = headers_with_authorization(&format!("Bearer {ADMIN_TOKEN}"));
+        assert_eq!(verify_admin(&config, &headers), Ok(()));
+    }
+
+    #[test]
+    fn admin_rejects_wrong_or_missing_token() {
+        let config = make_config(SECRET);
+        let headers = headers_with_authorization("Bearer wrong-token");
+        assert_eq!(verify_admin(&config, &headers), Err(VerifyError::TokenMismatch));
+        // The webhook secret is not an admin credential.
+        let headers = headers_with_authorization(&format!("Bearer {SECRET}"));
+        assert_eq!(verify_admin(&config, &headers), Err(VerifyError::TokenMismatch));
+        let headers = headers_with_authorization(ADMIN_TOKEN);
+        assert_eq!(verify_admin(&config, &headers), Err(VerifyError::MissingToken));
+        assert_eq!(verify_admin(&config, &HeaderMap::new()), Err(VerifyError::MissingToken));
+    }
+
+    #[test]
+    fn admin_rejects_everything_without_configured_token() {
+        let mut config = make_config(SECRET);
+        config.credential.admin_token = String::new();
+        let headers = headers_with_authorization("Bearer ");
+        assert_eq!(verify_admin(&config, &headers), Err(VerifyError::EmptyAdminToken));
+    }
+
+    const OWN_SECRET: &str = "own-webhook-secret";
+
+    fn config_with_own_secret() -> config::Config {
+        let mut config = make_config(SECRET);
+        config.deploy.push(config::Deploy {
+            repository: "a/own".to_string(),
+            github_webhook_secret: Some(config::WebhookSecrets(vec![OWN_SECRET.to_string()])),
+            ..Default::default()
+        });
+        config
+    }
+
+    fn signed(secret: &str, body: &[u8]) -> HeaderMap {
+        headers_with_signature(&format!("sha256={}", sign(secret.as_bytes(), body)))
+    }
+
+    #[test]
+    fn per_repository_secret_replaces_global_one() {
+        let config = config_with_own_secret();
+        let body = br#"{"repository":{"full_name":"a/own"}}"#;
+        assert_eq!(verify(&config, &signed(OWN_SECRET, body), body), ONE);
+        assert_eq!(
+            verify(&config, &signed(SECRET, body), body),
+            Err(VerifyError::SignatureMismatch),
+            "global secret must not be accepted for a repository with its own"
+        );
+    }
+
+    #[test]
+    fn per_repository_secret_only_signs_its_repository() {
+        let config = config_with_own_secret();
+        // Other repositories, known or not, and unparsable bodies use the global secret.
+        for body in [&br#"{"repository":{"full_name":"a/other"}}"#[..], b"{ not json", BODY] {
+            assert_eq!(verify(&config, &signed(SECRET, body), body), ONE);
+            assert_eq!(verify(&config, &signed(OWN_SECRET, body), body), Err(VerifyError::SignatureMismatch));
+        }
+    }
+
+    #[test]
+    fn any_of_several_secrets_verifies() {
+        let mut config = make_config(SECRET);
+        config.credential.github_webhook_secret.0.push("new-webhook-secret".to_string());
+
+        assert_eq!(verify(&config, &signed(SECRET, BODY), BODY), Ok(Matched { index: 0, of: 2 }));
+        assert_eq!(verify(&config, &signed("new-webhook-secret", BODY), BODY), Ok(Matched { index: 1, of: 2 }));
+        assert_eq!(verify(&config, &signed("wrong-secret", BODY), BODY), Err(VerifyError::SignatureMismatch));
+    }
 }